@@ -1,9 +1,8 @@
 use crate::ray::Ray;
 use crate::utils::*;
-use crate::vec3::Point3;
-use std::mem;
+use crate::vec3::{Point3, Vec3};
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct AABB {
     pub _min: Point3,
     pub _max: Point3,
@@ -15,16 +14,41 @@ impl AABB {
     }
 
     pub fn hit(&self, r: Ray, tmin: f64, tmax: f64) -> bool {
-        for a in 0..3 {
-            let inv_d: f64 = 1.0 / r.direction.axis(a);
-            let mut t0: f64 = (self._min.axis(a) - r.origin.axis(a)) * inv_d;
-            let mut t1: f64 = (self._max.axis(a) - r.origin.axis(a)) * inv_d;
-            if inv_d < 0.0 {
-                mem::swap(&mut t0, &mut t1);
-            }
+        let inv_dir = Vec3::new(
+            1.0 / r.direction.x,
+            1.0 / r.direction.y,
+            1.0 / r.direction.z,
+        );
+        let dir_is_neg = [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0];
+
+        self.hit_inv(r.origin, inv_dir, dir_is_neg, tmin, tmax)
+    }
+
+    // slab test with the inverse direction computed once per ray by the caller,
+    // dir_is_neg picks the near and far slab so that no swap is needed
+    pub fn hit_inv(
+        &self,
+        origin: Point3,
+        inv_dir: Vec3,
+        dir_is_neg: [bool; 3],
+        tmin: f64,
+        tmax: f64,
+    ) -> bool {
+        let mut tmin = tmin;
+        let mut tmax = tmax;
 
-            let tmin = if t0 > tmin { t0 } else { tmin };
-            let tmax = if t1 < tmax { t1 } else { tmax };
+        for (a, &neg) in dir_is_neg.iter().enumerate() {
+            let a = a as i32;
+            let (near, far) = if neg {
+                (self._max.axis(a), self._min.axis(a))
+            } else {
+                (self._min.axis(a), self._max.axis(a))
+            };
+            let t0: f64 = (near - origin.axis(a)) * inv_dir.axis(a);
+            let t1: f64 = (far - origin.axis(a)) * inv_dir.axis(a);
+
+            tmin = if t0 > tmin { t0 } else { tmin };
+            tmax = if t1 < tmax { t1 } else { tmax };
             if tmax <= tmin {
                 return false;
             }
@@ -32,6 +56,27 @@ impl AABB {
 
         true
     }
+
+    pub fn centroid(&self) -> Point3 {
+        (self._min + self._max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d: Vec3 = self._max - self._min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    // index of the axis along which the box is widest
+    pub fn maximum_extent(&self) -> i32 {
+        let d: Vec3 = self._max - self._min;
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
 }
 
 pub fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
@@ -48,3 +93,7 @@ pub fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
 
     AABB::new(small, big)
 }
+
+pub fn surrounding_point(bbox: &AABB, p: Point3) -> AABB {
+    surrounding_box(bbox, &AABB::new(p, p))
+}
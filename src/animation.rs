@@ -0,0 +1,254 @@
+use crate::utils::*;
+use crate::vec3::{Point3, Vec3};
+use std::ops::RangeInclusive;
+
+// Camera animation
+//
+// A camera path is a list of keyframes, each placing the camera at a time in
+// seconds. In between, the camera position, its target and its field of view
+// are interpolated: linearly, along a Catmull-Rom spline through the keys, or
+// along a single Bezier curve that uses the keys as its control points. A
+// sequence renders frames of a path to numbered files.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    pub time: f64,
+    pub lookfrom: Point3,
+    pub lookat: Point3,
+    pub vfov: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    // smooth and through every key, with tangents that account for uneven
+    // spacing in time
+    CatmullRom,
+    // smoothest, but only passes through the first and last key, the times of
+    // the others are ignored
+    Bezier,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraPath {
+    keys: Vec<Keyframe>,
+    interpolation: Interpolation,
+}
+
+// keyframes as plain numbers, interpolated component by component
+type Values = [f64; 7];
+
+fn values(key: &Keyframe) -> Values {
+    let (p, q) = (key.lookfrom, key.lookat);
+    [p.x, p.y, p.z, q.x, q.y, q.z, key.vfov]
+}
+
+fn keyframe(time: f64, v: &Values) -> Keyframe {
+    Keyframe {
+        time,
+        lookfrom: Point3::new(v[0], v[1], v[2]),
+        lookat: Point3::new(v[3], v[4], v[5]),
+        vfov: v[6],
+    }
+}
+
+// a * x + b * y, component by component
+fn combine(a: f64, x: &Values, b: f64, y: &Values) -> Values {
+    let mut v = [0.0; 7];
+    for i in 0..7 {
+        v[i] = a * x[i] + b * y[i];
+    }
+    v
+}
+
+impl CameraPath {
+    pub fn new(mut keys: Vec<Keyframe>, interpolation: Interpolation) -> Self {
+        assert!(
+            !keys.is_empty(),
+            "a camera path needs at least one keyframe"
+        );
+        keys.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        Self {
+            keys,
+            interpolation,
+        }
+    }
+
+    // A full circle around the vertical axis through `lookat` in `seconds`,
+    // starting from `lookfrom`. The keys run one step past both ends, so the
+    // spline has the same speed where the loop closes.
+    pub fn turntable(lookfrom: Point3, lookat: Point3, vfov: f64, seconds: f64) -> Self {
+        const STEPS: i32 = 16;
+        let offset = lookfrom - lookat;
+        let keys = (-1..=STEPS + 1)
+            .map(|i| {
+                let angle = 2.0 * PI * i as f64 / STEPS as f64;
+                let (sin, cos) = angle.sin_cos();
+                Keyframe {
+                    time: seconds * i as f64 / STEPS as f64,
+                    lookfrom: lookat
+                        + Vec3::new(
+                            offset.x * cos + offset.z * sin,
+                            offset.y,
+                            offset.z * cos - offset.x * sin,
+                        ),
+                    lookat,
+                    vfov,
+                }
+            })
+            .collect();
+        Self::new(keys, Interpolation::CatmullRom)
+    }
+
+    // the camera at `time`, which is held before the first and after the last key
+    pub fn at(&self, time: f64) -> Keyframe {
+        let keys = &self.keys;
+        let (first, last) = (keys[0].time, keys[keys.len() - 1].time);
+        if keys.len() == 1 || time <= first {
+            return keyframe(time, &values(&keys[0]));
+        }
+        if time >= last {
+            return keyframe(time, &values(&keys[keys.len() - 1]));
+        }
+
+        if self.interpolation == Interpolation::Bezier {
+            // de Casteljau
+            let u = (time - first) / (last - first);
+            let mut points: Vec<Values> = keys.iter().map(values).collect();
+            for n in (1..points.len()).rev() {
+                for i in 0..n {
+                    points[i] = combine(1.0 - u, &points[i], u, &points[i + 1]);
+                }
+            }
+            return keyframe(time, &points[0]);
+        }
+
+        // the segment between keys i and i + 1 holds `time`
+        let i = keys.iter().rposition(|k| k.time <= time).unwrap();
+        let (a, b) = (&keys[i], &keys[i + 1]);
+        let h = b.time - a.time;
+        let s = (time - a.time) / h;
+        let (p0, p1) = (values(a), values(b));
+        match self.interpolation {
+            Interpolation::Linear => keyframe(time, &combine(1.0 - s, &p0, s, &p1)),
+            _ => {
+                // cubic Hermite, the tangents are in units per second
+                let m0 = self.tangent(i);
+                let m1 = self.tangent(i + 1);
+                let (s2, s3) = (s * s, s * s * s);
+                let h00 = 2.0 * s3 - 3.0 * s2 + 1.0;
+                let h10 = s3 - 2.0 * s2 + s;
+                let h01 = -2.0 * s3 + 3.0 * s2;
+                let h11 = s3 - s2;
+                let v = combine(h00, &p0, h01, &p1);
+                let v = combine(1.0, &v, h10 * h, &m0);
+                keyframe(time, &combine(1.0, &v, h11 * h, &m1))
+            }
+        }
+    }
+
+    // Catmull-Rom tangent at key i, one-sided at the ends
+    fn tangent(&self, i: usize) -> Values {
+        let keys = &self.keys;
+        let before = if i == 0 { 0 } else { i - 1 };
+        let after = (i + 1).min(keys.len() - 1);
+        let dt = keys[after].time - keys[before].time;
+        combine(
+            1.0 / dt,
+            &values(&keys[after]),
+            -1.0 / dt,
+            &values(&keys[before]),
+        )
+    }
+}
+
+// Frames `first` to `last` of a camera path, frame 0 at time 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sequence {
+    pub path: CameraPath,
+    pub fps: f64,
+    pub first: u32,
+    pub last: u32,
+}
+
+impl Sequence {
+    pub fn frames(&self) -> RangeInclusive<u32> {
+        self.first..=self.last
+    }
+
+    pub fn camera(&self, frame: u32) -> Keyframe {
+        self.path.at(frame as f64 / self.fps)
+    }
+}
+
+// "scene.png" becomes "scene_0042.png" for frame 42
+pub fn frame_filename(filename: &str, frame: u32) -> String {
+    match filename.rfind('.') {
+        Some(dot) => format!("{}_{:04}{}", &filename[..dot], frame, &filename[dot..]),
+        None => format!("{}_{:04}", filename, frame),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(time: f64, x: f64, vfov: f64) -> Keyframe {
+        Keyframe {
+            time,
+            lookfrom: Point3::new(x, 0.0, 0.0),
+            lookat: Point3::zero(),
+            vfov,
+        }
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let keys = vec![
+            key(2.0, 4.0, 30.0),
+            key(0.0, 0.0, 20.0),
+            key(1.0, 1.0, 20.0),
+        ];
+        let linear = CameraPath::new(keys.clone(), Interpolation::Linear);
+        assert_eq!(linear.at(0.5).lookfrom.x, 0.5);
+        assert_eq!(linear.at(1.5).vfov, 25.0);
+        assert_eq!(linear.at(-1.0).lookfrom.x, 0.0);
+        assert_eq!(linear.at(5.0).lookfrom.x, 4.0);
+
+        // through every key, curving towards the faster second half
+        let spline = CameraPath::new(keys.clone(), Interpolation::CatmullRom);
+        assert!((spline.at(1.0).lookfrom.x - 1.0).abs() < 1e-12);
+        assert!(spline.at(0.5).lookfrom.x < 0.5);
+        assert!(spline.at(1.5).lookfrom.x < 2.5);
+
+        // a quadratic Bezier halfway: (0 + 2 * 1 + 4) / 4
+        let bezier = CameraPath::new(keys, Interpolation::Bezier);
+        assert!((bezier.at(1.0).lookfrom.x - 1.5).abs() < 1e-12);
+        assert!((bezier.at(2.0).lookfrom.x - 4.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_turntable_and_frames() {
+        let lookat = Point3::new(0.0, 1.0, 0.0);
+        let path = CameraPath::turntable(Point3::new(10.0, 3.0, 0.0), lookat, 40.0, 4.0);
+        for &t in &[0.0, 0.3, 1.0, 2.7, 4.0] {
+            let key = path.at(t);
+            let offset = key.lookfrom - lookat;
+            assert!(((offset.x * offset.x + offset.z * offset.z).sqrt() - 10.0).abs() < 0.01);
+            assert!((offset.y - 2.0).abs() < 1e-9);
+        }
+        // a quarter of the way round, starting from +x towards -z
+        let quarter = path.at(1.0).lookfrom;
+        assert!(quarter.x.abs() < 1e-9 && (quarter.z + 10.0).abs() < 1e-9);
+
+        let sequence = Sequence {
+            path,
+            fps: 24.0,
+            first: 12,
+            last: 14,
+        };
+        assert_eq!(sequence.frames().count(), 3);
+        assert_eq!(sequence.camera(24).lookfrom, quarter);
+        assert_eq!(frame_filename("maiden_room.png", 7), "maiden_room_0007.png");
+    }
+}
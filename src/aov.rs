@@ -0,0 +1,326 @@
+use crate::film::{self, quantize};
+use crate::utils::*;
+use crate::vec3::{Color, Point3, Vec3};
+use std::io::{self, Read, Write};
+
+// Arbitrary output variables
+//
+// Auxiliary buffers rendered alongside the beauty pass for compositing and
+// denoising. The geometric ones describe the first surface the camera ray of
+// a sample hits and are averaged over the samples of a pixel that hit
+// something. Object and material IDs can't be averaged, so a pixel keeps the
+// IDs of the hit nearest its center. Light groups split the radiance by the
+// emitter it came from, plus the background, and are filtered exactly like
+// the beauty so that they add up to it.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AovSettings {
+    // emitters in groups past the last one are counted in the last one
+    pub light_groups: usize,
+}
+
+// what a single camera sample saw
+#[derive(Clone, Debug)]
+pub struct AovSample {
+    pub hit: bool,
+    pub normal: Vec3,
+    pub albedo: Color,
+    pub depth: f64, // distance from the camera along the ray
+    pub position: Point3,
+    pub object_id: u32,
+    pub material_id: u32,
+    // one entry per light group, followed by the background
+    pub light_groups: Vec<Color>,
+}
+
+impl AovSample {
+    pub fn new(settings: &AovSettings) -> Self {
+        Self {
+            hit: false,
+            normal: Vec3::zero(),
+            albedo: Color::zero(),
+            depth: INF,
+            position: Point3::zero(),
+            object_id: 0,
+            material_id: 0,
+            light_groups: vec![Color::zero(); settings.light_groups.max(1) + 1],
+        }
+    }
+
+    // clears the sample for reuse without reallocating
+    pub fn reset(&mut self) {
+        self.hit = false;
+        self.normal = Vec3::zero();
+        self.albedo = Color::zero();
+        self.depth = INF;
+        self.position = Point3::zero();
+        self.object_id = 0;
+        self.material_id = 0;
+        for c in self.light_groups.iter_mut() {
+            *c = Color::zero();
+        }
+    }
+
+    pub fn add_light(&mut self, group: usize, radiance: Color) {
+        let last = self.light_groups.len() - 2;
+        self.light_groups[group.min(last)] += radiance;
+    }
+
+    pub fn add_background(&mut self, radiance: Color) {
+        let background = self.light_groups.len() - 1;
+        self.light_groups[background] += radiance;
+    }
+}
+
+// The first-hit buffers of a pixel. The sums are quantized like the film's,
+// and the nearest hit is decided on distance and then IDs, so merging gives
+// the same result in any order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AovPixel {
+    pub hits: u32,
+    pub normal: Vec3,
+    pub albedo: Color,
+    pub depth: f64,
+    pub position: Point3,
+    // squared distance of the nearest hit to the pixel center, and its IDs
+    pub nearest: f64,
+    pub object_id: u32,
+    pub material_id: u32,
+}
+
+impl Default for AovPixel {
+    fn default() -> Self {
+        Self {
+            hits: 0,
+            normal: Vec3::zero(),
+            albedo: Color::zero(),
+            depth: 0.0,
+            position: Point3::zero(),
+            nearest: INF,
+            object_id: 0,
+            material_id: 0,
+        }
+    }
+}
+
+fn quantize_vec(v: Vec3) -> Vec3 {
+    Vec3::new(quantize(v.x), quantize(v.y), quantize(v.z))
+}
+
+impl AovPixel {
+    // adds a sample taken at squared distance `d2` from the pixel center
+    pub fn add(&mut self, sample: &AovSample, d2: f64) {
+        if !sample.hit {
+            return;
+        }
+        self.merge(&AovPixel {
+            hits: 1,
+            normal: quantize_vec(sample.normal),
+            albedo: quantize_vec(sample.albedo),
+            depth: quantize(sample.depth),
+            position: quantize_vec(sample.position),
+            nearest: d2,
+            object_id: sample.object_id,
+            material_id: sample.material_id,
+        });
+    }
+
+    pub fn merge(&mut self, other: &AovPixel) {
+        self.hits += other.hits;
+        self.normal += other.normal;
+        self.albedo += other.albedo;
+        self.depth += other.depth;
+        self.position += other.position;
+        if (other.nearest, other.object_id, other.material_id)
+            < (self.nearest, self.object_id, self.material_id)
+        {
+            self.nearest = other.nearest;
+            self.object_id = other.object_id;
+            self.material_id = other.material_id;
+        }
+    }
+
+    // The averages over the hits. A pixel that only saw the background is
+    // infinitely deep and zero otherwise. Normals are renormalized, unless
+    // they cancel out entirely.
+    pub fn normal(&self) -> Vec3 {
+        if self.normal.squared_length() > 0.0 {
+            self.normal.unit()
+        } else {
+            Vec3::zero()
+        }
+    }
+
+    pub fn albedo(&self) -> Color {
+        self.average(self.albedo)
+    }
+
+    pub fn position(&self) -> Point3 {
+        self.average(self.position)
+    }
+
+    pub fn depth(&self) -> f64 {
+        match self.hits {
+            0 => INF,
+            n => self.depth / n as f64,
+        }
+    }
+
+    fn average(&self, sum: Vec3) -> Vec3 {
+        match self.hits {
+            0 => Vec3::zero(),
+            n => sum / n as f64,
+        }
+    }
+}
+
+// The AOVs of a region: first-hit buffers for its pixels, and the filtered
+// light groups, `stride` colors per pixel, which for a tile cover the same
+// footprint as its splatted radiance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AovBuffer {
+    pub stride: usize,
+    pub pixels: Vec<AovPixel>,
+    pub light_groups: Vec<Color>,
+}
+
+impl AovBuffer {
+    pub fn new(settings: &AovSettings, pixels: usize, footprint: usize) -> Self {
+        let stride = settings.light_groups.max(1) + 1;
+        Self {
+            stride,
+            pixels: vec![AovPixel::default(); pixels],
+            light_groups: vec![Color::zero(); footprint * stride],
+        }
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&(self.stride as u32).to_le_bytes())?;
+        for p in &self.pixels {
+            out.write_all(&p.hits.to_le_bytes())?;
+            for &v in &[
+                p.normal.x,
+                p.normal.y,
+                p.normal.z,
+                p.albedo.x,
+                p.albedo.y,
+                p.albedo.z,
+                p.depth,
+                p.position.x,
+                p.position.y,
+                p.position.z,
+                p.nearest,
+            ] {
+                out.write_all(&v.to_le_bytes())?;
+            }
+            out.write_all(&p.object_id.to_le_bytes())?;
+            out.write_all(&p.material_id.to_le_bytes())?;
+        }
+        for c in &self.light_groups {
+            out.write_all(&c.x.to_le_bytes())?;
+            out.write_all(&c.y.to_le_bytes())?;
+            out.write_all(&c.z.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    // reads a buffer of `pixels` pixels written by `write`
+    pub fn read(input: &mut impl Read, pixels: usize) -> io::Result<Self> {
+        let stride = film::read_u32(input)? as usize;
+        let mut buffer = Self {
+            stride,
+            pixels: Vec::with_capacity(pixels),
+            light_groups: Vec::with_capacity(pixels * stride),
+        };
+        for _ in 0..pixels {
+            let hits = film::read_u32(input)?;
+            let normal = read_vec3(input)?;
+            let albedo = read_vec3(input)?;
+            let depth = film::read_f64(input)?;
+            let position = read_vec3(input)?;
+            buffer.pixels.push(AovPixel {
+                hits,
+                normal,
+                albedo,
+                depth,
+                position,
+                nearest: film::read_f64(input)?,
+                object_id: film::read_u32(input)?,
+                material_id: film::read_u32(input)?,
+            });
+        }
+        for _ in 0..pixels * stride {
+            buffer.light_groups.push(read_vec3(input)?);
+        }
+
+        Ok(buffer)
+    }
+}
+
+fn read_vec3(input: &mut impl Read) -> io::Result<Vec3> {
+    Ok(Vec3::new(
+        film::read_f64(input)?,
+        film::read_f64(input)?,
+        film::read_f64(input)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(object_id: u32, depth: f64) -> AovSample {
+        let mut sample = AovSample::new(&AovSettings { light_groups: 2 });
+        sample.hit = true;
+        sample.normal = Vec3::new(0.0, 0.0, 1.0);
+        sample.albedo = Color::ones() * 0.5;
+        sample.depth = depth;
+        sample.object_id = object_id;
+        sample
+    }
+
+    #[test]
+    fn test_pixel_merge_is_order_independent() {
+        let samples = [
+            (sample(3, 1.0), 0.1),
+            (sample(1, 2.0), 0.05),
+            (sample(2, 3.0), 0.05),
+            (AovSample::new(&AovSettings { light_groups: 2 }), 0.0),
+        ];
+        let mut forward = AovPixel::default();
+        for (s, d2) in samples.iter() {
+            forward.add(s, *d2);
+        }
+        let mut a = AovPixel::default();
+        let mut b = AovPixel::default();
+        for (i, (s, d2)) in samples.iter().enumerate().rev() {
+            if i % 2 == 0 {
+                a.add(s, *d2);
+            } else {
+                b.add(s, *d2);
+            }
+        }
+        b.merge(&a);
+
+        assert_eq!(forward, b);
+        // the miss doesn't count, and the tie goes to the smaller ID
+        assert_eq!(forward.hits, 3);
+        assert_eq!(forward.depth(), 2.0);
+        assert_eq!(forward.object_id, 1);
+        assert_eq!(forward.normal(), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(AovPixel::default().depth(), INF);
+    }
+
+    #[test]
+    fn test_light_groups_fold_into_last() {
+        let mut sample = AovSample::new(&AovSettings { light_groups: 2 });
+        sample.add_light(0, Color::ones());
+        sample.add_light(5, Color::ones() * 2.0);
+        sample.add_background(Color::ones() * 3.0);
+        assert_eq!(
+            sample.light_groups,
+            vec![Color::ones(), Color::ones() * 2.0, Color::ones() * 3.0]
+        );
+    }
+}
@@ -1,11 +1,24 @@
 use crate::aabb::{self, AABB};
 use crate::hittable::{HitRecord, Hittable, HittableList};
 use crate::ray::Ray;
+use crate::stats;
 use crate::utils::*;
-use crate::vec3::Point3;
+use crate::vec3::{Point3, Vec3};
 use std::cmp::Ordering;
 use std::sync::Arc;
 
+const MAX_PRIMS_IN_LEAF: usize = 4;
+const SAH_BUCKETS: usize = 12;
+const SAH_TRAVERSAL_COST: f64 = 0.125;
+// Traversal keeps the pending nodes on a fixed stack, which holds at most one
+// entry per level. Past MEDIAN_DEPTH splits halve the primitives instead of
+// following the SAH, which can peel one primitive off per level, so leaves are
+// reached long before MAX_DEPTH; anything left there becomes a single leaf.
+pub(crate) const MAX_DEPTH: usize = 64;
+const MEDIAN_DEPTH: usize = 40;
+// rebuild once refitting has made the tree this much worse than when it was built
+const REBUILD_THRESHOLD: f64 = 1.5;
+
 pub struct BvhNode {
     pub left: Arc<dyn Hittable>,
     pub right: Arc<dyn Hittable>,
@@ -31,7 +44,7 @@ impl BvhNode {
                 right = objects[start].clone();
             }
         } else {
-            objects[start..end].sort_by(|a, b| comparator(&a, &b, axis));
+            objects[start..end].sort_by(|a, b| comparator(a, b, axis));
             let mid: usize = start + object_span / 2;
             left = Arc::new(BvhNode::new_(&mut objects[..], start, mid));
             right = Arc::new(BvhNode::new_(&mut objects[..], mid, end));
@@ -74,7 +87,7 @@ impl Hittable for BvhNode {
     }
 
     fn bounding_box(&self, output_box: &mut AABB) -> bool {
-        *output_box = self.bvh_box.clone();
+        *output_box = self.bvh_box;
 
         true
     }
@@ -97,3 +110,644 @@ fn comparator(a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>, ax: i32) -> Ordering
         false => Ordering::Greater,
     }
 }
+
+// Linear BVH
+//
+// The tree is flattened into a contiguous array in depth-first order, so the
+// first child of an interior node is always the next node and only the second
+// child's index has to be stored. Leaves refer to a run of `indices`, which in
+// turn point into `primitives` in their original insertion order. Primitives
+// without a bounding box (e.g. infinite planes) cannot be placed in the tree
+// and are tested one by one from the `unbounded` side list instead.
+//
+// For animation, primitives may be swapped in place with `set_primitive`.
+// `update` then refits the node bounds bottom-up without changing the tree
+// topology, and falls back to a full rebuild only when the refitted tree has
+// degraded too far in SAH cost.
+
+#[derive(Clone, Copy)]
+pub(crate) struct LinearBvhNode {
+    pub bbox: AABB,
+    pub offset: usize, // leaf: first entry in `indices`; interior: second child
+    pub n_primitives: usize,
+    pub axis: usize,
+}
+
+pub struct LinearBvh {
+    pub primitives: Vec<Arc<dyn Hittable>>,
+    pub(crate) indices: Vec<usize>,
+    pub(crate) unbounded: Vec<usize>,
+    pub(crate) nodes: Vec<LinearBvhNode>,
+    build_cost: f64,
+}
+
+#[derive(Clone, Copy)]
+struct PrimitiveInfo {
+    index: usize,
+    bbox: AABB,
+    centroid: Point3,
+}
+
+impl LinearBvh {
+    pub fn new(list: HittableList) -> Self {
+        Self::from_primitives(list.objects)
+    }
+
+    fn from_primitives(primitives: Vec<Arc<dyn Hittable>>) -> Self {
+        let mut info: Vec<PrimitiveInfo> = Vec::with_capacity(primitives.len());
+        let mut unbounded: Vec<usize> = Vec::new();
+
+        for (index, object) in primitives.iter().enumerate() {
+            let mut bbox = AABB::new(Point3::zero(), Point3::zero());
+            if object.bounding_box(&mut bbox) {
+                info.push(PrimitiveInfo {
+                    index,
+                    bbox,
+                    centroid: bbox.centroid(),
+                });
+            } else {
+                unbounded.push(index);
+            }
+        }
+
+        let mut bvh = Self {
+            primitives,
+            indices: Vec::with_capacity(info.len()),
+            unbounded,
+            nodes: Vec::with_capacity(2 * info.len()),
+            build_cost: 0.0,
+        };
+        if !info.is_empty() {
+            bvh.build(&mut info[..], 0);
+        }
+        bvh.build_cost = bvh.sah_cost();
+
+        bvh
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn set_primitive(&mut self, index: usize, object: Arc<dyn Hittable>) {
+        self.primitives[index] = object;
+    }
+
+    pub fn rebuild(&mut self) {
+        let primitives = std::mem::take(&mut self.primitives);
+        *self = Self::from_primitives(primitives);
+    }
+
+    // Recomputes every node's box from the current primitive boxes, children
+    // before parents. Returns false if a primitive gained or lost its bounding
+    // box, in which case only a rebuild can place it correctly.
+    pub fn refit(&mut self) -> bool {
+        for &i in &self.unbounded {
+            let mut bbox = AABB::new(Point3::zero(), Point3::zero());
+            if self.primitives[i].bounding_box(&mut bbox) {
+                return false;
+            }
+        }
+
+        // children are always stored after their parent
+        for n in (0..self.nodes.len()).rev() {
+            let node = self.nodes[n];
+            let bbox = if node.n_primitives > 0 {
+                let mut bbox: Option<AABB> = None;
+                for &i in &self.indices[node.offset..node.offset + node.n_primitives] {
+                    let mut b = AABB::new(Point3::zero(), Point3::zero());
+                    if !self.primitives[i].bounding_box(&mut b) {
+                        return false;
+                    }
+                    bbox = Some(match bbox {
+                        Some(bbox) => aabb::surrounding_box(&bbox, &b),
+                        None => b,
+                    });
+                }
+                bbox.unwrap_or(node.bbox)
+            } else {
+                aabb::surrounding_box(&self.nodes[n + 1].bbox, &self.nodes[node.offset].bbox)
+            };
+            self.nodes[n].bbox = bbox;
+        }
+
+        true
+    }
+
+    // Refits after primitives have moved, rebuilding instead when that is no
+    // longer good enough. Returns whether a full rebuild happened.
+    pub fn update(&mut self) -> bool {
+        if !self.refit() || self.sah_cost() > self.build_cost * REBUILD_THRESHOLD {
+            self.rebuild();
+            return true;
+        }
+
+        false
+    }
+
+    // expected cost of tracing a ray through the tree relative to the root box
+    pub fn sah_cost(&self) -> f64 {
+        let root_area = match self.nodes.first() {
+            Some(root) if root.bbox.surface_area() > 0.0 => root.bbox.surface_area(),
+            _ => return 0.0,
+        };
+
+        self.nodes
+            .iter()
+            .map(|node| {
+                let weight = node.bbox.surface_area() / root_area;
+                if node.n_primitives > 0 {
+                    weight * node.n_primitives as f64
+                } else {
+                    weight * SAH_TRAVERSAL_COST
+                }
+            })
+            .sum()
+    }
+
+    // appends the subtree over `info` to `nodes` and returns its root index
+    fn build(&mut self, info: &mut [PrimitiveInfo], depth: usize) -> usize {
+        let node_index = self.nodes.len();
+        let bbox = info
+            .iter()
+            .skip(1)
+            .fold(info[0].bbox, |b, p| aabb::surrounding_box(&b, &p.bbox));
+        let centroid_box = info
+            .iter()
+            .skip(1)
+            .fold(AABB::new(info[0].centroid, info[0].centroid), |b, p| {
+                aabb::surrounding_point(&b, p.centroid)
+            });
+        let axis = centroid_box.maximum_extent();
+        let extent = centroid_box._max.axis(axis) - centroid_box._min.axis(axis);
+
+        // all centroids coincide, no split can separate them
+        if info.len() == 1 || extent <= 0.0 || depth >= MAX_DEPTH {
+            return self.push_leaf(bbox, info);
+        }
+
+        let sah = if depth < MEDIAN_DEPTH {
+            split_sah(info, &bbox, &centroid_box, axis)
+        } else {
+            None
+        };
+        let mid = match sah {
+            Some(mid) => mid,
+            None if info.len() <= MAX_PRIMS_IN_LEAF => return self.push_leaf(bbox, info),
+            None => {
+                info.sort_by(|a, b| {
+                    a.centroid
+                        .axis(axis)
+                        .partial_cmp(&b.centroid.axis(axis))
+                        .unwrap_or(Ordering::Equal)
+                });
+                info.len() / 2
+            }
+        };
+
+        self.nodes.push(LinearBvhNode {
+            bbox,
+            offset: 0,
+            n_primitives: 0,
+            axis: axis as usize,
+        });
+        let (left, right) = info.split_at_mut(mid);
+        self.build(left, depth + 1);
+        let second = self.build(right, depth + 1);
+        self.nodes[node_index].offset = second;
+
+        node_index
+    }
+
+    fn push_leaf(&mut self, bbox: AABB, info: &[PrimitiveInfo]) -> usize {
+        self.nodes.push(LinearBvhNode {
+            bbox,
+            offset: self.indices.len(),
+            n_primitives: info.len(),
+            axis: 0,
+        });
+        self.indices.extend(info.iter().map(|p| p.index));
+
+        self.nodes.len() - 1
+    }
+}
+
+// Binned surface area heuristic. Returns the partition point after reordering
+// `info`, or None when keeping the primitives in one leaf is cheaper.
+fn split_sah(
+    info: &mut [PrimitiveInfo],
+    bbox: &AABB,
+    centroid_box: &AABB,
+    axis: i32,
+) -> Option<usize> {
+    let lo = centroid_box._min.axis(axis);
+    let extent = centroid_box._max.axis(axis) - lo;
+    let bucket_of = |p: &PrimitiveInfo| -> usize {
+        let b = ((p.centroid.axis(axis) - lo) / extent * SAH_BUCKETS as f64) as usize;
+        b.min(SAH_BUCKETS - 1)
+    };
+
+    let mut counts = [0usize; SAH_BUCKETS];
+    let mut bounds: [Option<AABB>; SAH_BUCKETS] = [None; SAH_BUCKETS];
+    for p in info.iter() {
+        let b = bucket_of(p);
+        counts[b] += 1;
+        bounds[b] = Some(match bounds[b] {
+            Some(bb) => aabb::surrounding_box(&bb, &p.bbox),
+            None => p.bbox,
+        });
+    }
+
+    let mut best_cost = INF;
+    let mut best_split = 0;
+    for split in 0..SAH_BUCKETS - 1 {
+        let (n0, b0) = merge_buckets(&counts[..=split], &bounds[..=split]);
+        let (n1, b1) = merge_buckets(&counts[split + 1..], &bounds[split + 1..]);
+        let area = |b: Option<AABB>| b.map_or(0.0, |b| b.surface_area());
+        let cost = SAH_TRAVERSAL_COST
+            + (n0 as f64 * area(b0) + n1 as f64 * area(b1)) / bbox.surface_area();
+        if cost < best_cost {
+            best_cost = cost;
+            best_split = split;
+        }
+    }
+
+    let leaf_cost = info.len() as f64;
+    if info.len() <= MAX_PRIMS_IN_LEAF && leaf_cost <= best_cost {
+        return None;
+    }
+
+    let mut mid = 0;
+    for i in 0..info.len() {
+        if bucket_of(&info[i]) <= best_split {
+            info.swap(i, mid);
+            mid += 1;
+        }
+    }
+
+    if mid == 0 || mid == info.len() {
+        None
+    } else {
+        Some(mid)
+    }
+}
+
+fn merge_buckets(counts: &[usize], bounds: &[Option<AABB>]) -> (usize, Option<AABB>) {
+    let n = counts.iter().sum();
+    let bbox = bounds
+        .iter()
+        .fold(None, |acc: Option<AABB>, b| match (acc, b) {
+            (Some(a), Some(b)) => Some(aabb::surrounding_box(&a, b)),
+            (None, Some(b)) => Some(*b),
+            (acc, None) => acc,
+        });
+
+    (n, bbox)
+}
+
+impl Hittable for LinearBvh {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let mut hit_anything = false;
+        let mut closest_so_far = t_max;
+
+        for &i in &self.unbounded {
+            if self.primitives[i].hit(r, t_min, closest_so_far, rec) {
+                hit_anything = true;
+                closest_so_far = rec.t;
+            }
+        }
+
+        if self.nodes.is_empty() {
+            stats::record(|c| c.primitive_tests += self.unbounded.len() as u64);
+            return hit_anything;
+        }
+
+        let inv_dir = Vec3::new(
+            1.0 / r.direction.x,
+            1.0 / r.direction.y,
+            1.0 / r.direction.z,
+        );
+        let dir_is_neg = [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0];
+
+        let mut stack = [0usize; MAX_DEPTH];
+        let mut to_visit = 0;
+        let mut current = 0;
+        let mut node_visits = 0;
+        let mut primitive_tests = self.unbounded.len() as u64;
+
+        loop {
+            let node = &self.nodes[current];
+            node_visits += 1;
+            if node
+                .bbox
+                .hit_inv(r.origin, inv_dir, dir_is_neg, t_min, closest_so_far)
+            {
+                if node.n_primitives > 0 {
+                    primitive_tests += node.n_primitives as u64;
+                    for &i in &self.indices[node.offset..node.offset + node.n_primitives] {
+                        if self.primitives[i].hit(r, t_min, closest_so_far, rec) {
+                            hit_anything = true;
+                            closest_so_far = rec.t;
+                        }
+                    }
+                } else {
+                    // visit the child nearer to the ray origin first
+                    if dir_is_neg[node.axis] {
+                        stack[to_visit] = current + 1;
+                        current = node.offset;
+                    } else {
+                        stack[to_visit] = node.offset;
+                        current += 1;
+                    }
+                    to_visit += 1;
+                    continue;
+                }
+            }
+
+            if to_visit == 0 {
+                break;
+            }
+            to_visit -= 1;
+            current = stack[to_visit];
+        }
+
+        stats::record(|c| {
+            c.node_visits += node_visits;
+            c.primitive_tests += primitive_tests;
+        });
+        hit_anything
+    }
+
+    fn bounding_box(&self, output_box: &mut AABB) -> bool {
+        if !self.unbounded.is_empty() {
+            return false;
+        }
+
+        match self.nodes.first() {
+            Some(root) => {
+                *output_box = root.bbox;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::instance::Instance;
+    use crate::material::Material;
+    use crate::scene;
+    use crate::transform::Transform;
+    use std::time::Instant;
+
+    // for spheres and hit records whose shading doesn't matter
+    fn dummy_material() -> Arc<dyn Material> {
+        Arc::new(crate::Lambertian::new(Arc::new(crate::SolidColor::new(
+            Point3::ones(),
+        ))))
+    }
+
+    pub(crate) fn dummy_record() -> HitRecord {
+        HitRecord::new(dummy_material())
+    }
+
+    // spheres at x = 1, 2, 4, ..., every split the SAH finds peels off the
+    // outermost one, so the tree is as deep as it can get
+    pub(crate) fn power_of_two_spheres(n: i32) -> HittableList {
+        let mut list = HittableList::new();
+        for i in 0..n {
+            list.add(Arc::new(crate::Sphere::new(
+                Point3::new(2f64.powi(i), 0.0, 0.0),
+                0.5,
+                dummy_material(),
+            )));
+        }
+        list
+    }
+
+    fn random_ray(origin: Point3, target: Point3, spread: f64) -> Ray {
+        let target = target + Vec3::random_range(-spread, spread);
+        Ray::new(origin, target - origin)
+    }
+
+    fn rays_per_second(world: &dyn Hittable, rays: &[Ray]) -> f64 {
+        let mut rec = dummy_record();
+        let start = Instant::now();
+        for &r in rays {
+            world.hit(r, 0.001, INF, &mut rec);
+        }
+        rays.len() as f64 / start.elapsed().as_secs_f64()
+    }
+
+    #[test]
+    fn test_linear_bvh_matches_list() {
+        let list = scene::random_scene();
+        let bvh = LinearBvh::new(list.clone());
+        let mut rec_list = dummy_record();
+        let mut rec_bvh = rec_list.clone();
+
+        for _ in 0..2000 {
+            let r = random_ray(
+                Vec3::random_range(-15.0, 15.0) + Vec3::new(0.0, -3.0, 0.0),
+                Point3::zero(),
+                8.0,
+            );
+            let hit_list = list.hit(r, 0.001, INF, &mut rec_list);
+            let hit_bvh = bvh.hit(r, 0.001, INF, &mut rec_bvh);
+            assert_eq!(hit_list, hit_bvh);
+            if hit_list {
+                assert_eq!(rec_list.t, rec_bvh.t);
+                assert_eq!(rec_list.p, rec_bvh.p);
+            }
+        }
+    }
+
+    // an infinite plane y = k, which has no bounding box
+    struct Plane {
+        k: f64,
+    }
+
+    impl Hittable for Plane {
+        fn hit(&self, r: Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+            let t = (self.k - r.origin.y) / r.direction.y;
+            if t < t_min || t > t_max {
+                return false;
+            }
+            rec.t = t;
+            rec.p = r.at(t);
+            true
+        }
+
+        fn bounding_box(&self, _output_box: &mut AABB) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_linear_bvh_unbounded() {
+        let mut list = scene::random_scene();
+        list.add(Arc::new(Plane { k: -0.5 }));
+        let bvh = LinearBvh::new(list.clone());
+        let mut output_box = AABB::new(Point3::zero(), Point3::zero());
+        assert!(!bvh.bounding_box(&mut output_box));
+
+        let mut rec_list = dummy_record();
+        let mut rec_bvh = rec_list.clone();
+        for _ in 0..500 {
+            let r = random_ray(Point3::new(13.0, -2.0, 3.0), Point3::zero(), 8.0);
+            assert_eq!(
+                list.hit(r, 0.001, INF, &mut rec_list),
+                bvh.hit(r, 0.001, INF, &mut rec_bvh)
+            );
+            assert_eq!(rec_list.t, rec_bvh.t);
+        }
+    }
+
+    fn sphere_instances(n: usize) -> (Arc<dyn Hittable>, Vec<Transform>) {
+        let sphere: Arc<dyn Hittable> =
+            Arc::new(crate::Sphere::new(Point3::zero(), 0.5, dummy_material()));
+        let transforms = (0..n)
+            .map(|i| Transform::translate(Vec3::new(i as f64, 0.0, 0.0)))
+            .collect();
+        (sphere, transforms)
+    }
+
+    fn assert_same_hits(a: &dyn Hittable, b: &dyn Hittable, origin: Point3, target: Point3) {
+        let mut rec_a = dummy_record();
+        let mut rec_b = rec_a.clone();
+        for _ in 0..500 {
+            let r = random_ray(origin, target, 20.0);
+            assert_eq!(
+                a.hit(r, 0.001, INF, &mut rec_a),
+                b.hit(r, 0.001, INF, &mut rec_b)
+            );
+            assert_eq!(rec_a.t, rec_b.t);
+        }
+    }
+
+    #[test]
+    fn test_linear_bvh_refit() {
+        let (sphere, transforms) = sphere_instances(64);
+        let mut list = HittableList::new();
+        for t in &transforms {
+            list.add(Arc::new(Instance::new(sphere.clone(), *t, None)));
+        }
+        let mut bvh = LinearBvh::new(list);
+        let node_count = bvh.node_count();
+
+        // lift every sphere a little, the topology stays good enough to refit
+        let mut moved = HittableList::new();
+        for (i, t) in transforms.iter().enumerate() {
+            let t = t.then(&Transform::translate(Vec3::new(0.0, 0.1 * i as f64, 0.0)));
+            let instance: Arc<dyn Hittable> = Arc::new(Instance::new(sphere.clone(), t, None));
+            bvh.set_primitive(i, instance.clone());
+            moved.add(instance);
+        }
+        assert!(!bvh.update());
+        assert_eq!(bvh.node_count(), node_count);
+        assert_same_hits(
+            &bvh,
+            &moved,
+            Point3::new(30.0, 3.0, -40.0),
+            Point3::new(30.0, 3.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_linear_bvh_rebuild_when_degraded() {
+        let (sphere, transforms) = sphere_instances(64);
+        let mut list = HittableList::new();
+        for t in &transforms {
+            list.add(Arc::new(Instance::new(sphere.clone(), *t, None)));
+        }
+        let mut bvh = LinearBvh::new(list);
+
+        // reverse the order in a zig-zag so neighbouring leaves end up far apart
+        let mut moved = HittableList::new();
+        for i in 0..transforms.len() {
+            let j = if i % 2 == 0 { i } else { transforms.len() - i };
+            let instance: Arc<dyn Hittable> =
+                Arc::new(Instance::new(sphere.clone(), transforms[j], None));
+            bvh.set_primitive(i, instance.clone());
+            moved.add(instance);
+        }
+        assert!(bvh.update());
+        assert_same_hits(
+            &bvh,
+            &moved,
+            Point3::new(30.0, 0.0, -40.0),
+            Point3::new(30.0, 0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_linear_bvh_depth_is_capped() {
+        let list = power_of_two_spheres(1000);
+        let bvh = LinearBvh::new(list.clone());
+        let mut rec_list = dummy_record();
+        let mut rec_bvh = rec_list.clone();
+        let r = Ray::new(Point3::new(-1.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(bvh.hit(r, 0.001, INF, &mut rec_bvh));
+        assert!(list.hit(r, 0.001, INF, &mut rec_list));
+        assert_eq!(rec_list.t, rec_bvh.t);
+    }
+
+    #[test]
+    fn test_linear_bvh_empty() {
+        let bvh = LinearBvh::new(HittableList::new());
+        let mut output_box = AABB::new(Point3::zero(), Point3::zero());
+        assert!(!bvh.bounding_box(&mut output_box));
+        assert_eq!(bvh.node_count(), 0);
+    }
+
+    // cargo test --release bench_bvh -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_bvh() {
+        let scenes: Vec<(&str, HittableList, Point3, Point3, f64)> = vec![
+            (
+                "random_scene",
+                scene::random_scene(),
+                Point3::new(13.0, -2.0, 3.0),
+                Point3::zero(),
+                3.0,
+            ),
+            (
+                "final_scene",
+                scene::final_scene(),
+                Point3::new(478.0, -278.0, -600.0),
+                Point3::new(278.0, -278.0, 0.0),
+                250.0,
+            ),
+            (
+                "maiden_room",
+                scene::maiden_room(),
+                Point3::new(26.0, -26.0, 6.0),
+                Point3::new(0.0, -2.3, 0.0),
+                4.0,
+            ),
+        ];
+
+        for (name, mut list, lookfrom, lookat, spread) in scenes {
+            let rays: Vec<Ray> = (0..200_000)
+                .map(|_| random_ray(lookfrom, lookat, spread))
+                .collect();
+            let linear = LinearBvh::new(list.clone());
+            let wide = crate::Bvh4::new(list.clone());
+
+            let recursive = BvhNode::new(&mut list);
+
+            println!(
+                "{}: list {:.0} rays/s, bvh_node {:.0} rays/s, linear_bvh {:.0} rays/s, bvh4 ({:?}) {:.0} rays/s",
+                name,
+                rays_per_second(&list, &rays),
+                rays_per_second(&recursive, &rays),
+                rays_per_second(&linear, &rays),
+                wide.simd_level(),
+                rays_per_second(&wide, &rays),
+            );
+        }
+    }
+}
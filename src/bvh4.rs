@@ -0,0 +1,559 @@
+use crate::aabb::AABB;
+use crate::bvh::{LinearBvh, MAX_DEPTH};
+use crate::hittable::{HitRecord, Hittable, HittableList};
+use crate::ray::Ray;
+use crate::stats;
+use std::sync::Arc;
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+// 4-wide BVH
+//
+// Built by collapsing a LinearBvh so that every node holds up to four children,
+// whose boxes are stored as structure-of-arrays and tested against a ray in one
+// go. The box test is written three times: a scalar loop, SSE2 (two lanes at a
+// time, always present on x86_64) and AVX (all four lanes, detected at run
+// time). All of them perform the same IEEE operations in the same order as
+// AABB::hit_inv, so they agree bit for bit with each other and the scalar path.
+
+const WIDTH: usize = 4;
+// A wide node lies at least one binary level below its parent and leaves three
+// siblings behind on the stack when its nearest child is visited.
+const STACK_SIZE: usize = 1 + (WIDTH - 1) * MAX_DEPTH;
+
+#[derive(Clone, Copy)]
+struct Bvh4Node {
+    // bounds[0] holds the min corners, bounds[1] the max corners, per axis
+    bounds: [[[f64; WIDTH]; 3]; 2],
+    child: [usize; WIDTH], // interior: node index; leaf: first entry in `indices`
+    n_primitives: [usize; WIDTH], // 0 for an interior child
+    n_children: usize,
+}
+
+impl Bvh4Node {
+    fn empty() -> Self {
+        // an inverted box, which no ray can hit
+        Self {
+            bounds: [[[f64::INFINITY; WIDTH]; 3], [[f64::NEG_INFINITY; WIDTH]; 3]],
+            child: [0; WIDTH],
+            n_primitives: [0; WIDTH],
+            n_children: 0,
+        }
+    }
+
+    fn set_bounds(&mut self, lane: usize, bbox: &AABB) {
+        for a in 0..3 {
+            self.bounds[0][a][lane] = bbox._min.axis(a as i32);
+            self.bounds[1][a][lane] = bbox._max.axis(a as i32);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SimdLevel {
+    Scalar,
+    Sse2,
+    Avx,
+}
+
+impl SimdLevel {
+    #[cfg(target_arch = "x86_64")]
+    pub fn detect() -> Self {
+        if is_x86_feature_detected!("avx") {
+            SimdLevel::Avx
+        } else {
+            SimdLevel::Sse2
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn detect() -> Self {
+        SimdLevel::Scalar
+    }
+
+    // the best level up to this one that the CPU supports
+    #[cfg(target_arch = "x86_64")]
+    pub fn supported(self) -> Self {
+        match self {
+            SimdLevel::Avx if !is_x86_feature_detected!("avx") => SimdLevel::Sse2,
+            level => level,
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn supported(self) -> Self {
+        SimdLevel::Scalar
+    }
+}
+
+pub struct Bvh4 {
+    pub primitives: Vec<Arc<dyn Hittable>>,
+    indices: Vec<usize>,
+    unbounded: Vec<usize>,
+    nodes: Vec<Bvh4Node>,
+    root_box: Option<AABB>,
+    simd: SimdLevel,
+}
+
+impl Bvh4 {
+    pub fn new(list: HittableList) -> Self {
+        Self::from_linear(LinearBvh::new(list), SimdLevel::detect())
+    }
+
+    // Levels the CPU lacks are lowered to one it has, so `intersect` may rely
+    // on `simd` being supported.
+    pub fn from_linear(bvh: LinearBvh, simd: SimdLevel) -> Self {
+        let mut wide = Self {
+            primitives: Vec::new(),
+            indices: Vec::new(),
+            unbounded: Vec::new(),
+            nodes: Vec::with_capacity(bvh.nodes.len() / 2 + 1),
+            root_box: bvh.nodes.first().map(|root| root.bbox),
+            simd: simd.supported(),
+        };
+        if !bvh.nodes.is_empty() {
+            wide.collapse(&bvh, 0);
+        }
+        wide.primitives = bvh.primitives;
+        wide.indices = bvh.indices;
+        wide.unbounded = bvh.unbounded;
+
+        wide
+    }
+
+    pub fn simd_level(&self) -> SimdLevel {
+        self.simd
+    }
+
+    // pulls the children of binary node `n` up into one wide node, opening the
+    // largest interior child until there are four lanes or only leaves remain
+    fn collapse(&mut self, bvh: &LinearBvh, n: usize) -> usize {
+        let node_index = self.nodes.len();
+        self.nodes.push(Bvh4Node::empty());
+
+        let binary = &bvh.nodes;
+        let mut lanes: Vec<usize> = if binary[n].n_primitives > 0 {
+            vec![n]
+        } else {
+            vec![n + 1, binary[n].offset]
+        };
+        while lanes.len() < WIDTH {
+            let largest = lanes
+                .iter()
+                .enumerate()
+                .filter(|(_, &b)| binary[b].n_primitives == 0)
+                .max_by(|(_, &a), (_, &b)| {
+                    let area_a = binary[a].bbox.surface_area();
+                    let area_b = binary[b].bbox.surface_area();
+                    area_a
+                        .partial_cmp(&area_b)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .map(|(i, _)| i);
+            match largest {
+                Some(i) => {
+                    let b = lanes.swap_remove(i);
+                    lanes.push(b + 1);
+                    lanes.push(binary[b].offset);
+                }
+                None => break,
+            }
+        }
+
+        for (lane, &b) in lanes.iter().enumerate() {
+            let child = if binary[b].n_primitives > 0 {
+                binary[b].offset
+            } else {
+                self.collapse(bvh, b)
+            };
+            let node = &mut self.nodes[node_index];
+            node.set_bounds(lane, &binary[b].bbox);
+            node.child[lane] = child;
+            node.n_primitives[lane] = binary[b].n_primitives;
+        }
+        self.nodes[node_index].n_children = lanes.len();
+
+        node_index
+    }
+
+    fn intersect(
+        &self,
+        node: &Bvh4Node,
+        ray: &RayData,
+        t_min: f64,
+        t_max: f64,
+    ) -> (u32, [f64; WIDTH]) {
+        match self.simd {
+            // SAFETY: from_linear only keeps Avx if the CPU was detected to
+            // support it
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx => unsafe { intersect_avx(node, ray, t_min, t_max) },
+            // SAFETY: SSE2 is part of the x86_64 baseline
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Sse2 => unsafe { intersect_sse2(node, ray, t_min, t_max) },
+            _ => intersect_scalar(node, ray, t_min, t_max),
+        }
+    }
+}
+
+struct RayData {
+    origin: [f64; 3],
+    inv_dir: [f64; 3],
+    dir_is_neg: [usize; 3],
+}
+
+impl RayData {
+    fn new(r: &Ray) -> Self {
+        let inv_dir = [
+            1.0 / r.direction.x,
+            1.0 / r.direction.y,
+            1.0 / r.direction.z,
+        ];
+        Self {
+            origin: [r.origin.x, r.origin.y, r.origin.z],
+            inv_dir,
+            dir_is_neg: [
+                (inv_dir[0] < 0.0) as usize,
+                (inv_dir[1] < 0.0) as usize,
+                (inv_dir[2] < 0.0) as usize,
+            ],
+        }
+    }
+}
+
+// Returns a bit mask of the lanes whose box the ray enters within
+// [t_min, t_max], and the entry distance of every lane.
+fn intersect_scalar(node: &Bvh4Node, ray: &RayData, t_min: f64, t_max: f64) -> (u32, [f64; WIDTH]) {
+    let mut tn = [t_min; WIDTH];
+    let mut tf = [t_max; WIDTH];
+
+    for a in 0..3 {
+        let near = &node.bounds[ray.dir_is_neg[a]][a];
+        let far = &node.bounds[1 - ray.dir_is_neg[a]][a];
+        for lane in 0..WIDTH {
+            let t0 = (near[lane] - ray.origin[a]) * ray.inv_dir[a];
+            let t1 = (far[lane] - ray.origin[a]) * ray.inv_dir[a];
+            tn[lane] = if t0 > tn[lane] { t0 } else { tn[lane] };
+            tf[lane] = if t1 < tf[lane] { t1 } else { tf[lane] };
+        }
+    }
+
+    // written as a negated <= to mirror the SIMD compare below
+    let mut mask = 0;
+    for lane in 0..WIDTH {
+        #[allow(clippy::neg_cmp_op_on_partial_ord)]
+        if !(tf[lane] <= tn[lane]) {
+            mask |= 1 << lane;
+        }
+    }
+
+    (mask, tn)
+}
+
+// _mm_max_pd(a, b) and _mm_min_pd(a, b) return b unless a compares greater
+// (less), which is exactly the scalar selection above, NaN included.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn intersect_sse2(
+    node: &Bvh4Node,
+    ray: &RayData,
+    t_min: f64,
+    t_max: f64,
+) -> (u32, [f64; WIDTH]) {
+    let mut tn_out = [0.0; WIDTH];
+    let mut mask = 0;
+
+    for half in 0..WIDTH / 2 {
+        let lane = 2 * half;
+        let mut tn = _mm_set1_pd(t_min);
+        let mut tf = _mm_set1_pd(t_max);
+        for a in 0..3 {
+            let near = _mm_loadu_pd(node.bounds[ray.dir_is_neg[a]][a].as_ptr().add(lane));
+            let far = _mm_loadu_pd(node.bounds[1 - ray.dir_is_neg[a]][a].as_ptr().add(lane));
+            let origin = _mm_set1_pd(ray.origin[a]);
+            let inv_dir = _mm_set1_pd(ray.inv_dir[a]);
+            let t0 = _mm_mul_pd(_mm_sub_pd(near, origin), inv_dir);
+            let t1 = _mm_mul_pd(_mm_sub_pd(far, origin), inv_dir);
+            tn = _mm_max_pd(t0, tn);
+            tf = _mm_min_pd(t1, tf);
+        }
+        let miss = _mm_movemask_pd(_mm_cmple_pd(tf, tn)) as u32;
+        mask |= (!miss & 0b11) << lane;
+        _mm_storeu_pd(tn_out.as_mut_ptr().add(lane), tn);
+    }
+
+    (mask, tn_out)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn intersect_avx(
+    node: &Bvh4Node,
+    ray: &RayData,
+    t_min: f64,
+    t_max: f64,
+) -> (u32, [f64; WIDTH]) {
+    let mut tn_out = [0.0; WIDTH];
+    let mut tn = _mm256_set1_pd(t_min);
+    let mut tf = _mm256_set1_pd(t_max);
+
+    for a in 0..3 {
+        let near = _mm256_loadu_pd(node.bounds[ray.dir_is_neg[a]][a].as_ptr());
+        let far = _mm256_loadu_pd(node.bounds[1 - ray.dir_is_neg[a]][a].as_ptr());
+        let origin = _mm256_set1_pd(ray.origin[a]);
+        let inv_dir = _mm256_set1_pd(ray.inv_dir[a]);
+        let t0 = _mm256_mul_pd(_mm256_sub_pd(near, origin), inv_dir);
+        let t1 = _mm256_mul_pd(_mm256_sub_pd(far, origin), inv_dir);
+        tn = _mm256_max_pd(t0, tn);
+        tf = _mm256_min_pd(t1, tf);
+    }
+    let miss = _mm256_movemask_pd(_mm256_cmp_pd(tf, tn, _CMP_LE_OQ)) as u32;
+    _mm256_storeu_pd(tn_out.as_mut_ptr(), tn);
+
+    (!miss & 0b1111, tn_out)
+}
+
+impl Hittable for Bvh4 {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let mut hit_anything = false;
+        let mut closest_so_far = t_max;
+
+        for &i in &self.unbounded {
+            if self.primitives[i].hit(r, t_min, closest_so_far, rec) {
+                hit_anything = true;
+                closest_so_far = rec.t;
+            }
+        }
+
+        if self.nodes.is_empty() {
+            stats::record(|c| c.primitive_tests += self.unbounded.len() as u64);
+            return hit_anything;
+        }
+
+        // every stack entry is a child lane: (child, n_primitives, entry distance)
+        let ray = RayData::new(&r);
+        let mut stack = [(0usize, 0usize, 0.0f64); STACK_SIZE];
+        stack[0] = (0, 0, t_min);
+        let mut to_visit = 1;
+        let mut node_visits = 0;
+        let mut primitive_tests = self.unbounded.len() as u64;
+
+        while to_visit > 0 {
+            to_visit -= 1;
+            let (child, n_primitives, entry) = stack[to_visit];
+
+            // a closer hit may have been found since the lane was pushed, and
+            // this is the same outcome the box test with the new t_max gives
+            if entry >= closest_so_far {
+                continue;
+            }
+
+            if n_primitives > 0 {
+                primitive_tests += n_primitives as u64;
+                for &i in &self.indices[child..child + n_primitives] {
+                    if self.primitives[i].hit(r, t_min, closest_so_far, rec) {
+                        hit_anything = true;
+                        closest_so_far = rec.t;
+                    }
+                }
+                continue;
+            }
+
+            let node = &self.nodes[child];
+            node_visits += 1;
+            let (mask, tn) = self.intersect(node, &ray, t_min, closest_so_far);
+
+            // hit lanes sorted back to front, so that the nearest is popped first
+            let mut order = [0usize; WIDTH];
+            let mut n_hit = 0;
+            for lane in 0..node.n_children {
+                if mask & (1 << lane) == 0 {
+                    continue;
+                }
+                let mut k = n_hit;
+                while k > 0 && tn[order[k - 1]] < tn[lane] {
+                    order[k] = order[k - 1];
+                    k -= 1;
+                }
+                order[k] = lane;
+                n_hit += 1;
+            }
+
+            for &lane in &order[..n_hit] {
+                stack[to_visit] = (node.child[lane], node.n_primitives[lane], tn[lane]);
+                to_visit += 1;
+            }
+        }
+
+        stats::record(|c| {
+            c.node_visits += node_visits;
+            c.primitive_tests += primitive_tests;
+        });
+        hit_anything
+    }
+
+    fn bounding_box(&self, output_box: &mut AABB) -> bool {
+        if !self.unbounded.is_empty() {
+            return false;
+        }
+
+        match self.root_box {
+            Some(bbox) => {
+                *output_box = bbox;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::tests::{dummy_record, power_of_two_spheres};
+    use crate::scene;
+    use crate::utils::*;
+    use crate::vec3::{Point3, Vec3};
+
+    fn levels() -> Vec<SimdLevel> {
+        let mut levels = vec![SimdLevel::Scalar];
+        #[cfg(target_arch = "x86_64")]
+        {
+            levels.push(SimdLevel::Sse2);
+            if is_x86_feature_detected!("avx") {
+                levels.push(SimdLevel::Avx);
+            }
+        }
+        levels
+    }
+
+    // mostly random values, with zeros and repeats mixed in so that axis
+    // aligned rays and rays starting on a slab produce infinities and NaNs
+    fn random_coord() -> f64 {
+        match random_i32_range(0, 9) {
+            0 => 0.0,
+            1 => 1.0,
+            _ => random_f64_range(-2.0, 2.0),
+        }
+    }
+
+    #[test]
+    fn test_box_test_matches_scalar() {
+        let wides: Vec<Bvh4> = levels()
+            .into_iter()
+            .map(|level| Bvh4::from_linear(LinearBvh::new(HittableList::new()), level))
+            .collect();
+
+        for _ in 0..20000 {
+            let mut node = Bvh4Node::empty();
+            node.n_children = WIDTH;
+            for lane in 0..WIDTH {
+                let a = Point3::new(random_coord(), random_coord(), random_coord());
+                let b = Point3::new(random_coord(), random_coord(), random_coord());
+                let bbox = AABB::new(
+                    Point3::new(fmin(a.x, b.x), fmin(a.y, b.y), fmin(a.z, b.z)),
+                    Point3::new(fmax(a.x, b.x), fmax(a.y, b.y), fmax(a.z, b.z)),
+                );
+                node.set_bounds(lane, &bbox);
+            }
+            let r = Ray::new(
+                Point3::new(random_coord(), random_coord(), random_coord()),
+                Vec3::new(random_coord(), random_coord(), random_coord()),
+            );
+            let ray = RayData::new(&r);
+            let (t_min, t_max) = (0.001, random_f64_range(0.0, 10.0));
+
+            let (mask, tn) = intersect_scalar(&node, &ray, t_min, t_max);
+            for lane in 0..WIDTH {
+                let bbox = AABB::new(
+                    Point3::new(
+                        node.bounds[0][0][lane],
+                        node.bounds[0][1][lane],
+                        node.bounds[0][2][lane],
+                    ),
+                    Point3::new(
+                        node.bounds[1][0][lane],
+                        node.bounds[1][1][lane],
+                        node.bounds[1][2][lane],
+                    ),
+                );
+                let scalar_hit = bbox.hit_inv(
+                    r.origin,
+                    Vec3::new(ray.inv_dir[0], ray.inv_dir[1], ray.inv_dir[2]),
+                    [
+                        ray.dir_is_neg[0] == 1,
+                        ray.dir_is_neg[1] == 1,
+                        ray.dir_is_neg[2] == 1,
+                    ],
+                    t_min,
+                    t_max,
+                );
+                assert_eq!(mask & (1 << lane) != 0, scalar_hit);
+            }
+
+            for wide in &wides {
+                let level = wide.simd_level();
+                let (simd_mask, simd_tn) = wide.intersect(&node, &ray, t_min, t_max);
+                assert_eq!(simd_mask, mask, "{:?}", level);
+                for lane in 0..WIDTH {
+                    if mask & (1 << lane) != 0 {
+                        assert_eq!(simd_tn[lane].to_bits(), tn[lane].to_bits(), "{:?}", level);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unsupported_levels_are_lowered() {
+        let wide = |level| Bvh4::from_linear(LinearBvh::new(HittableList::new()), level);
+        assert_eq!(wide(SimdLevel::Scalar).simd_level(), SimdLevel::Scalar);
+        assert_eq!(wide(SimdLevel::Avx).simd_level(), *levels().last().unwrap());
+    }
+
+    #[test]
+    fn test_bvh4_deep_tree() {
+        // the SAH peels one sphere off per level, down to the depth cap
+        let list = power_of_two_spheres(1000);
+        let linear = LinearBvh::new(list.clone());
+        let mut rec_linear = dummy_record();
+        let mut rec_wide = rec_linear.clone();
+        for level in levels() {
+            let wide = Bvh4::from_linear(LinearBvh::new(list.clone()), level);
+            // from before the chain, and from inside spheres along it
+            for &k in &[-1, 3, 20, 40] {
+                let origin = Point3::new(if k < 0 { -1.0 } else { 2f64.powi(k) }, 0.0, 0.0);
+                let r = Ray::new(origin, Vec3::new(1.0, 0.0, 0.0));
+                assert!(linear.hit(r, 0.001, INF, &mut rec_linear));
+                assert!(wide.hit(r, 0.001, INF, &mut rec_wide));
+                assert_eq!(rec_linear.t, rec_wide.t, "{:?}", level);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bvh4_matches_linear_bvh() {
+        let list = scene::maiden_room();
+        let linear = LinearBvh::new(list.clone());
+
+        for level in levels() {
+            let wide = Bvh4::from_linear(LinearBvh::new(list.clone()), level);
+            let mut rec_linear = dummy_record();
+            let mut rec_wide = rec_linear.clone();
+
+            for _ in 0..5000 {
+                let origin = Point3::new(26.0, -26.0, 6.0) + Vec3::random_range(-10.0, 10.0);
+                let target = Point3::new(0.0, -2.3, 0.0) + Vec3::random_range(-12.0, 12.0);
+                let r = Ray::new(origin, target - origin);
+                let hit_linear = linear.hit(r, 0.001, INF, &mut rec_linear);
+                let hit_wide = wide.hit(r, 0.001, INF, &mut rec_wide);
+                assert_eq!(hit_linear, hit_wide, "{:?}", level);
+                if hit_linear {
+                    assert_eq!(rec_linear.t.to_bits(), rec_wide.t.to_bits(), "{:?}", level);
+                    assert_eq!(rec_linear.p, rec_wide.p, "{:?}", level);
+                }
+            }
+        }
+    }
+}
@@ -1,8 +1,52 @@
+use crate::lens::Lens;
 use crate::ray::Ray;
 use crate::utils::*;
-use crate::vec3::{self, Point3, Vec3};
+use crate::vec3::{Point3, Vec3};
 
-#[derive(Copy, Clone)]
+// Cameras
+//
+// A camera model turns a point (s, t) on the image, both in [0, 1], into a
+// ray. s runs along u (the right of the camera) and t along v (its up), w
+// points back from the view direction. Models may leave parts of the image
+// unseen, like the corners of a circular fisheye, which are rendered black.
+
+pub trait CameraModel: Send + Sync {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray>;
+}
+
+// the projections main can pick from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    Perspective,
+    // `height` is the size of the view in world units
+    Orthographic { height: f64 },
+    // `fov` in degrees across the image circle, which fits the shorter side
+    Fisheye { fov: f64 },
+    Equirectangular,
+    CubeMap,
+}
+
+impl Projection {
+    // panoramas need a fixed image shape
+    pub fn aspect_ratio(&self) -> Option<f64> {
+        match self {
+            Projection::Equirectangular => Some(2.0),
+            Projection::CubeMap => Some(6.0),
+            _ => None,
+        }
+    }
+}
+
+// the right, up and backward axes of a camera at `lookfrom`
+fn basis(lookfrom: Point3, lookat: Point3, vup: Vec3) -> (Vec3, Vec3, Vec3) {
+    let w: Vec3 = (lookfrom - lookat).unit();
+    let u: Vec3 = vup.cross(w).unit();
+    let v: Vec3 = w.cross(u);
+    (u, v, w)
+}
+
+// Thin-lens perspective camera, sharp at `focus_dist`.
+#[derive(Clone)]
 pub struct Camera {
     origin: Point3,
     lower_left_corner: Point3,
@@ -10,7 +54,13 @@ pub struct Camera {
     vertical: Vec3,
     u: Vec3,
     v: Vec3,
+    w: Vec3,
     lens_radius: f64,
+    focus_dist: f64,
+    aspect_ratio: f64,
+    lens: Lens,
+    // of the plane in focus when the lens is tilted
+    focus_normal: Option<Vec3>,
 }
 
 impl Camera {
@@ -28,9 +78,7 @@ impl Camera {
         let viewport_height: f64 = 2.0 * h;
         let viewport_width: f64 = aspect_ratio * viewport_height;
 
-        let w: Vec3 = (lookfrom - lookat).unit();
-        let u: Vec3 = vup.cross(w).unit();
-        let v: Vec3 = w.cross(u);
+        let (u, v, w) = basis(lookfrom, lookat, vup);
 
         let origin: Point3 = lookfrom;
         let horizontal: Vec3 = u * viewport_width * focus_dist;
@@ -45,17 +93,342 @@ impl Camera {
             vertical,
             u,
             v,
+            w,
             lens_radius,
+            focus_dist,
+            aspect_ratio,
+            lens: Lens::default(),
+            focus_normal: None,
+        }
+    }
+
+    pub fn with_lens(mut self, lens: Lens) -> Self {
+        let (pitch, yaw) = lens.tilt;
+        self.focus_normal = if pitch == 0.0 && yaw == 0.0 {
+            None
+        } else {
+            Some(
+                (-self.w
+                    + self.v * degrees_to_radians(pitch).tan()
+                    + self.u * degrees_to_radians(yaw).tan())
+                .unit(),
+            )
+        };
+        let (x, y) = lens.shift;
+        self.lower_left_corner += self.horizontal * x + self.vertical * y;
+        self.lens = lens;
+        self
+    }
+
+    // A copy moved `offset` along the right axis, for one eye of a stereo
+    // pair. Its window moves back by offset * focus_dist / convergence, so
+    // that both eyes frame the same rectangle at the convergence distance.
+    pub fn eye(&self, offset: f64, convergence: Option<f64>) -> Self {
+        let mut eye = self.clone();
+        let d = self.u * offset;
+        eye.origin += d;
+        eye.lower_left_corner += d;
+        if let Some(convergence) = convergence {
+            eye.lower_left_corner -= d * (self.focus_dist / convergence);
+        }
+        eye
+    }
+}
+
+impl CameraModel for Camera {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        // the lens sees the image from -1 to 1, center to corners
+        let corner = (self.aspect_ratio * self.aspect_ratio + 1.0).sqrt();
+        let (a, b) = self.lens.sample(
+            (2.0 * s - 1.0) * self.aspect_ratio / corner,
+            (2.0 * t - 1.0) / corner,
+        )?;
+        let offset: Vec3 = (self.u * a + self.v * b) * self.lens_radius;
+
+        // rays through the lens meet where the ray through its center meets
+        // the plane in focus
+        let target = self.lower_left_corner + self.horizontal * s + self.vertical * t;
+        let focus = match self.focus_normal {
+            Some(n) => {
+                let d = target - self.origin;
+                let along = n * d;
+                if along > 1e-9 {
+                    self.origin + d * (n * (-self.w * self.focus_dist) / along)
+                } else {
+                    target
+                }
+            }
+            None => target,
+        };
+
+        Some(Ray::new(self.origin + offset, focus - self.origin - offset))
+    }
+}
+
+// Parallel rays, so sizes don't shrink with distance. The rays start on the
+// plane through `lookfrom`, anything behind it is cut away.
+#[derive(Copy, Clone)]
+pub struct Orthographic {
+    lower_left_corner: Point3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    direction: Vec3,
+}
+
+impl Orthographic {
+    pub fn new(
+        lookfrom: Point3,
+        lookat: Point3,
+        vup: Vec3,
+        height: f64,
+        aspect_ratio: f64,
+    ) -> Self {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        let horizontal = u * height * aspect_ratio;
+        let vertical = v * height;
+
+        Self {
+            lower_left_corner: lookfrom - horizontal / 2.0 - vertical / 2.0,
+            horizontal,
+            vertical,
+            direction: -w,
+        }
+    }
+}
+
+impl CameraModel for Orthographic {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        Some(Ray::new(
+            self.lower_left_corner + self.horizontal * s + self.vertical * t,
+            self.direction,
+        ))
+    }
+}
+
+// Equidistant fisheye: the angle from the view direction grows linearly with
+// the distance from the image center, up to fov / 2 at the edge of the image
+// circle. Fields of view past 180 degrees see behind the camera.
+#[derive(Copy, Clone)]
+pub struct Fisheye {
+    origin: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    half_fov: f64,
+    aspect_ratio: f64,
+}
+
+impl Fisheye {
+    pub fn new(lookfrom: Point3, lookat: Point3, vup: Vec3, fov: f64, aspect_ratio: f64) -> Self {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+
+        Self {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            half_fov: degrees_to_radians(fov.min(360.0)) / 2.0,
+            aspect_ratio,
         }
     }
+}
+
+impl CameraModel for Fisheye {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        // in units of the radius of the image circle
+        let radius = self.aspect_ratio.min(1.0);
+        let x = (2.0 * s - 1.0) * self.aspect_ratio / radius;
+        let y = (2.0 * t - 1.0) / radius;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
 
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
-        let rd: Vec3 = vec3::random_in_unit_disk() * self.lens_radius;
-        let offset: Vec3 = self.u * rd.x + self.v * rd.y;
+        let theta = r * self.half_fov;
+        let phi = y.atan2(x);
+        let direction =
+            -self.w * theta.cos() + (self.u * phi.cos() + self.v * phi.sin()) * theta.sin();
+        Some(Ray::new(self.origin, direction))
+    }
+}
 
-        Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + self.horizontal * s + self.vertical * t - self.origin - offset,
-        )
+// The whole sphere around `lookfrom`, longitude along s with the view
+// direction in the middle and latitude along t.
+#[derive(Copy, Clone)]
+pub struct Equirectangular {
+    origin: Point3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Equirectangular {
+    pub fn new(lookfrom: Point3, lookat: Point3, vup: Vec3) -> Self {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        Self {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+        }
+    }
+
+    // the direction seen at (s, t)
+    pub fn direction(&self, s: f64, t: f64) -> Vec3 {
+        let longitude = (s - 0.5) * 2.0 * PI;
+        let latitude = (t - 0.5) * PI;
+        (self.u * longitude.sin() - self.w * longitude.cos()) * latitude.cos()
+            + self.v * latitude.sin()
+    }
+
+    // the horizontal direction to the right of the one seen at s
+    pub fn right(&self, s: f64) -> Vec3 {
+        let longitude = (s - 0.5) * 2.0 * PI;
+        self.u * longitude.cos() + self.w * longitude.sin()
+    }
+}
+
+impl CameraModel for Equirectangular {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        Some(Ray::new(self.origin, self.direction(s, t)))
+    }
+}
+
+// Six 90 degree views side by side in a 6:1 strip, in the order right, left,
+// up, down, front and back, each seen from inside the cube.
+#[derive(Copy, Clone)]
+pub struct CubeMap {
+    origin: Point3,
+    // forward, right and up of every face
+    faces: [(Vec3, Vec3, Vec3); 6],
+}
+
+impl CubeMap {
+    pub fn new(lookfrom: Point3, lookat: Point3, vup: Vec3) -> Self {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        Self {
+            origin: lookfrom,
+            faces: [
+                (u, w, v),
+                (-u, -w, v),
+                (v, u, w),
+                (-v, u, -w),
+                (-w, u, v),
+                (w, -u, v),
+            ],
+        }
+    }
+}
+
+impl CameraModel for CubeMap {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        let face = ((s * 6.0) as usize).min(5);
+        let a = s * 6.0 - face as f64;
+        let (forward, right, up) = self.faces[face];
+        let direction = forward + right * (2.0 * a - 1.0) + up * (2.0 * t - 1.0);
+        Some(Ray::new(self.origin, direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOKFROM: Point3 = Point3 {
+        x: 1.0,
+        y: 2.0,
+        z: 3.0,
+    };
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_projections_look_at_lookat() {
+        let lookat = Point3::new(1.0, 2.0, -7.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+        let forward = Vec3::new(0.0, 0.0, -1.0);
+        let cameras: Vec<Box<dyn CameraModel>> = vec![
+            Box::new(Camera::new(LOOKFROM, lookat, vup, 40.0, 2.0, 0.0, 10.0)),
+            Box::new(Orthographic::new(LOOKFROM, lookat, vup, 4.0, 2.0)),
+            Box::new(Fisheye::new(LOOKFROM, lookat, vup, 180.0, 2.0)),
+            Box::new(Equirectangular::new(LOOKFROM, lookat, vup)),
+        ];
+        for cam in &cameras {
+            let ray = cam.get_ray(0.5, 0.5).unwrap();
+            assert_close(ray.direction.unit(), forward);
+            assert_close(ray.origin, LOOKFROM);
+        }
+        // the front face of the cube map is the fifth
+        let cube = CubeMap::new(LOOKFROM, lookat, vup);
+        assert_close(cube.get_ray(4.5 / 6.0, 0.5).unwrap().direction, forward);
+    }
+
+    #[test]
+    fn test_panorama_and_fisheye_edges() {
+        let lookat = Point3::new(1.0, 2.0, -7.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+
+        let fisheye = Fisheye::new(LOOKFROM, lookat, vup, 180.0, 2.0);
+        // the image circle touches the top and bottom edges
+        let side = fisheye.get_ray(0.5, 1.0).unwrap().direction;
+        assert_close(side, vup);
+        assert!(fisheye.get_ray(0.0, 0.5).is_none());
+
+        let panorama = Equirectangular::new(LOOKFROM, lookat, vup);
+        assert_close(panorama.direction(0.0, 0.5), Vec3::new(0.0, 0.0, 1.0));
+        assert_close(panorama.direction(0.75, 0.5), Vec3::new(1.0, 0.0, 0.0));
+        assert_close(panorama.direction(0.3, 1.0), vup);
+
+        // neighbouring faces of the cube map meet along their shared edges
+        let cube = CubeMap::new(LOOKFROM, lookat, vup);
+        let right = cube.get_ray(0.0, 0.5).unwrap().direction;
+        let front = cube.get_ray(5.0 / 6.0 - 1e-12, 0.5).unwrap().direction;
+        assert_close(right.unit(), Vec3::new(1.0, 0.0, -1.0).unit());
+        assert_close(front.unit(), Vec3::new(1.0, 0.0, -1.0).unit());
+    }
+
+    #[test]
+    fn test_tilted_focus_plane() {
+        let lens = Lens {
+            tilt: (45.0, 0.0),
+            ..Lens::default()
+        };
+        let lookat = Point3::new(0.0, 0.0, -10.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+        let cam = Camera::new(Point3::zero(), lookat, vup, 40.0, 1.0, 2.0, 10.0).with_lens(lens);
+
+        // rays through all of the lens meet on the plane y - z = 10, which is
+        // nearer than 10 towards the top of the view
+        let top = cam.get_ray(0.5, 1.0).unwrap().at(1.0);
+        assert!((top.y - top.z - 10.0).abs() < 1e-9);
+        assert!(top.z > -8.0);
+        for _ in 0..8 {
+            let ray = cam.get_ray(0.5, 1.0).unwrap();
+            assert_close(ray.at(1.0), top);
+        }
+        // and cross the axis at the focus distance
+        assert_close(cam.get_ray(0.5, 0.5).unwrap().at(1.0), lookat);
+    }
+
+    #[test]
+    fn test_shifted_frame() {
+        let lens = Lens {
+            shift: (0.5, -0.25),
+            ..Lens::default()
+        };
+        let lookat = Point3::new(0.0, 0.0, -10.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+        let cam = Camera::new(Point3::zero(), lookat, vup, 40.0, 1.0, 0.0, 10.0);
+        let shifted = cam.clone().with_lens(lens);
+
+        // the frame slides within the same plane, which still faces lookat
+        for &(s, t) in &[(0.0, 0.0), (1.0, 0.0), (0.5, 0.5), (0.0, 1.0), (1.0, 1.0)] {
+            let p = shifted.get_ray(s, t).unwrap().at(1.0);
+            assert_close(p, cam.get_ray(s + 0.5, t - 0.25).unwrap().at(1.0));
+            assert!((p.z + 10.0).abs() < 1e-9);
+        }
+        assert!(shifted.get_ray(0.5, 0.5).unwrap().direction.x > 0.0);
     }
 }
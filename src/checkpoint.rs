@@ -0,0 +1,154 @@
+use crate::film::{self, Film};
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+// Checkpoint
+//
+// Everything needed to continue an interrupted progressive render: the film,
+// how many passes it holds, and the seed. The random numbers of a sample only
+// depend on the seed, the pixel and how many samples the pixel already has, so
+// the film and the seed fully describe the random state. The scene and
+// settings hashes guard against resuming into a different render.
+
+const MAGIC: &[u8; 8] = b"RTCKPT06";
+
+pub struct Checkpoint {
+    pub scene_hash: u64,
+    pub settings_hash: u64,
+    pub seed: u64,
+    pub passes_done: u32,
+    pub film: Film,
+}
+
+impl Checkpoint {
+    // written next to `path` first and then renamed, like image snapshots
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        {
+            let mut out = BufWriter::new(File::create(&tmp_path)?);
+            out.write_all(MAGIC)?;
+            out.write_all(&self.scene_hash.to_le_bytes())?;
+            out.write_all(&self.settings_hash.to_le_bytes())?;
+            out.write_all(&self.seed.to_le_bytes())?;
+            out.write_all(&self.passes_done.to_le_bytes())?;
+            self.film.write(&mut out)?;
+            out.flush()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a render checkpoint", path),
+            ));
+        }
+
+        Ok(Self {
+            scene_hash: film::read_u64(&mut input)?,
+            settings_hash: film::read_u64(&mut input)?,
+            seed: film::read_u64(&mut input)?,
+            passes_done: film::read_u32(&mut input)?,
+            film: Film::read(&mut input)?,
+        })
+    }
+}
+
+// 64-bit FNV-1a. Unlike DefaultHasher its output is fixed across Rust
+// versions, so checkpoints written by one build can be checked by another.
+pub struct FnvHasher(u64);
+
+impl FnvHasher {
+    pub fn new() -> Self {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write_f64(&mut self, x: f64) {
+        self.write_u64(x.to_bits());
+    }
+}
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::film::{TileSamples, Welford};
+    use crate::scheduler::Tile;
+    use crate::vec3::Color;
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let mut film = Film::new(3, 2);
+        let tile = Tile {
+            index: 0,
+            x0: 0,
+            y0: 0,
+            x1: 3,
+            y1: 2,
+        };
+        let sum = (0..6).map(|i| Color::new(i as f64, 0.5, -1.0)).collect();
+        let stats = (0..6)
+            .map(|i| Welford {
+                n: 7,
+                mean: i as f64,
+                m2: 0.25,
+            })
+            .collect();
+        let result = TileSamples {
+            bounds: tile,
+            sum,
+            weight: vec![7.0; 6],
+            abs_weight: vec![7.0; 6],
+            stats,
+            aovs: None,
+        };
+        film.add_tile(&tile, &result);
+
+        let checkpoint = Checkpoint {
+            scene_hash: 1,
+            settings_hash: 2,
+            seed: 3,
+            passes_done: 4,
+            film,
+        };
+        let path = std::env::temp_dir().join("raytracer_checkpoint_test.bin");
+        let path = path.to_str().unwrap();
+        checkpoint.save(path).unwrap();
+        let loaded = Checkpoint::load(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.scene_hash, 1);
+        assert_eq!(loaded.settings_hash, 2);
+        assert_eq!(loaded.seed, 3);
+        assert_eq!(loaded.passes_done, 4);
+        for y in 0..2 {
+            for x in 0..3 {
+                assert_eq!(loaded.film.pixel(x, y), checkpoint.film.pixel(x, y));
+                assert_eq!(loaded.film.stats(x, y), checkpoint.film.stats(x, y));
+            }
+        }
+    }
+}
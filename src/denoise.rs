@@ -0,0 +1,290 @@
+use crate::film::{self, Film};
+use crate::vec3::{Color, Vec3};
+
+// Denoiser
+//
+// An edge-avoiding à-trous wavelet filter in the style of SVGF. Each pass is
+// a 5x5 B-spline kernel whose taps are spaced twice as far apart as in the
+// pass before, so a few passes cover a large footprint cheaply. Every tap is
+// weighted down where the albedo or normal buffers show an edge, and where the
+// luminance differs by more than the pixel's noise explains. The variance of
+// the estimate is filtered along with it, so later passes smooth less as the
+// image gets cleaner. Without AOVs on the film only the luminance guides it.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Denoiser {
+    // passes of the filter, the footprint is 4 * (2^iterations - 1) + 1 wide
+    pub iterations: u32,
+    // how many standard deviations of noise a luminance difference may span
+    // and still be smoothed over, larger values blur more
+    pub sigma_luminance: f64,
+    // exponent of the cosine between normals, larger values keep more edges
+    pub sigma_normal: f64,
+    // albedo difference at which taps start being ignored
+    pub sigma_albedo: f64,
+    // blend from the noisy (0) to the filtered (1) image
+    pub strength: f64,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_luminance: 4.0,
+            sigma_normal: 128.0,
+            sigma_albedo: 0.1,
+            strength: 1.0,
+        }
+    }
+}
+
+// the guides of a pixel, `hit` is false where only the background was seen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Feature {
+    pub hit: bool,
+    pub normal: Vec3,
+    pub albedo: Color,
+}
+
+// the noise of unsampled pixels, large enough for them to be smoothed over
+const MAX_VARIANCE: f64 = 1e4;
+
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// a copy of the film with its estimate denoised
+pub fn denoise(film: &Film, denoiser: &Denoiser) -> Film {
+    let coords: Vec<(u32, u32)> = (0..film.height)
+        .flat_map(|y| (0..film.width).map(move |x| (x, y)))
+        .collect();
+    let color: Vec<Color> = coords.iter().map(|&(x, y)| film.pixel(x, y)).collect();
+    let variance: Vec<f64> = coords
+        .iter()
+        .map(|&(x, y)| {
+            let stats = film.stats(x, y);
+            match stats.n {
+                0 | 1 => MAX_VARIANCE,
+                n => (stats.variance() / n as f64).min(MAX_VARIANCE),
+            }
+        })
+        .collect();
+    let features: Option<Vec<Feature>> = film.aov(0, 0).map(|_| {
+        coords
+            .iter()
+            .map(|&(x, y)| {
+                let aov = film.aov(x, y).unwrap();
+                Feature {
+                    hit: aov.hits > 0,
+                    normal: aov.normal(),
+                    albedo: aov.albedo(),
+                }
+            })
+            .collect()
+    });
+
+    let filtered = atrous(
+        film.width as usize,
+        film.height as usize,
+        &color,
+        &variance,
+        features.as_deref(),
+        denoiser,
+    );
+    let t = denoiser.strength;
+    film.with_pixels(
+        color
+            .iter()
+            .zip(filtered.iter())
+            .map(|(&noisy, &clean)| noisy * (1.0 - t) + clean * t)
+            .collect(),
+    )
+}
+
+pub fn atrous(
+    width: usize,
+    height: usize,
+    color: &[Color],
+    variance: &[f64],
+    features: Option<&[Feature]>,
+    denoiser: &Denoiser,
+) -> Vec<Color> {
+    let mut color = color.to_vec();
+    let mut variance = variance.to_vec();
+
+    for pass in 0..denoiser.iterations {
+        let step = 1i64 << pass;
+        let blurred = blur_variance(width, height, &variance);
+        let mut next_color = color.clone();
+        let mut next_variance = variance.clone();
+
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let p = y as usize * width + x as usize;
+                let lum_p = film::luminance(color[p]);
+                let sigma_l = denoiser.sigma_luminance * blurred[p].sqrt() + 1e-6;
+
+                let mut sum = Color::zero();
+                let mut sum_variance = 0.0;
+                let mut sum_weight = 0.0;
+                for (i, hy) in KERNEL.iter().enumerate() {
+                    let qy = y + (i as i64 - 2) * step;
+                    if qy < 0 || qy >= height as i64 {
+                        continue;
+                    }
+                    for (j, hx) in KERNEL.iter().enumerate() {
+                        let qx = x + (j as i64 - 2) * step;
+                        if qx < 0 || qx >= width as i64 {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+
+                        let lum_q = film::luminance(color[q]);
+                        let mut w = hx * hy * (-(lum_p - lum_q).abs() / sigma_l).exp();
+                        if let Some(features) = features {
+                            w *= feature_weight(&features[p], &features[q], denoiser);
+                        }
+                        sum += color[q] * w;
+                        sum_variance += variance[q] * w * w;
+                        sum_weight += w;
+                    }
+                }
+
+                // the center tap always counts, so the weight can't be zero
+                next_color[p] = sum / sum_weight;
+                next_variance[p] = sum_variance / (sum_weight * sum_weight);
+            }
+        }
+
+        color = next_color;
+        variance = next_variance;
+    }
+
+    color
+}
+
+fn feature_weight(p: &Feature, q: &Feature, denoiser: &Denoiser) -> f64 {
+    match (p.hit, q.hit) {
+        (false, false) => 1.0,
+        (true, true) => {
+            let cos = (p.normal * q.normal).max(0.0);
+            let d = p.albedo - q.albedo;
+            cos.powf(denoiser.sigma_normal)
+                * (-d.squared_length() / (denoiser.sigma_albedo * denoiser.sigma_albedo)).exp()
+        }
+        // the background and a surface don't mix
+        _ => 0.0,
+    }
+}
+
+// 3x3 Gaussian of the variance, which is itself too noisy to steer by
+fn blur_variance(width: usize, height: usize, variance: &[f64]) -> Vec<f64> {
+    const G: [f64; 3] = [0.25, 0.5, 0.25];
+    let mut blurred = vec![0.0; variance.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0.0;
+            let mut sum_weight = 0.0;
+            for (i, gy) in G.iter().enumerate() {
+                for (j, gx) in G.iter().enumerate() {
+                    let (qx, qy) = (x as i64 + j as i64 - 1, y as i64 + i as i64 - 1);
+                    if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                        continue;
+                    }
+                    sum += variance[qy as usize * width + qx as usize] * gx * gy;
+                    sum_weight += gx * gy;
+                }
+            }
+            blurred[y * width + x] = sum / sum_weight;
+        }
+    }
+
+    blurred
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::*;
+
+    const SIZE: usize = 32;
+
+    // a gray image with white noise of variance 0.01, left half in front of a
+    // red wall and right half in front of a blue one
+    fn noisy() -> (Vec<Color>, Vec<f64>, Vec<Feature>) {
+        seed_rng(7);
+        let mut color = Vec::new();
+        let mut features = Vec::new();
+        for _ in 0..SIZE {
+            for x in 0..SIZE {
+                let left = x < SIZE / 2;
+                let base = if left { 0.5 } else { 0.1 };
+                let noise = (random_f64() - 0.5) * 12f64.sqrt() * 0.1;
+                color.push(Color::ones() * (base + noise));
+                features.push(Feature {
+                    hit: true,
+                    normal: Vec3::new(0.0, 0.0, 1.0),
+                    albedo: if left {
+                        Color::new(1.0, 0.0, 0.0)
+                    } else {
+                        Color::new(0.0, 0.0, 1.0)
+                    },
+                });
+            }
+        }
+        (color, vec![0.01; SIZE * SIZE], features)
+    }
+
+    fn error(color: &[Color], columns: std::ops::Range<usize>, expected: f64) -> f64 {
+        let mut sum = 0.0;
+        for y in 0..SIZE {
+            for x in columns.clone() {
+                let d = color[y * SIZE + x].y - expected;
+                sum += d * d;
+            }
+        }
+        (sum / (SIZE * columns.len()) as f64).sqrt()
+    }
+
+    #[test]
+    fn test_denoise_reduces_noise() {
+        let (color, variance, features) = noisy();
+        let denoised = atrous(
+            SIZE,
+            SIZE,
+            &color,
+            &variance,
+            Some(&features),
+            &Denoiser::default(),
+        );
+
+        for (columns, expected) in [(0..SIZE / 2, 0.5), (SIZE / 2..SIZE, 0.1)].iter().cloned() {
+            let before = error(&color, columns.clone(), expected);
+            let after = error(&denoised, columns, expected);
+            assert!(after < before / 3.0, "{} -> {}", before, after);
+        }
+    }
+
+    #[test]
+    fn test_denoise_keeps_albedo_edges() {
+        let (color, variance, features) = noisy();
+        let guided = atrous(
+            SIZE,
+            SIZE,
+            &color,
+            &variance,
+            Some(&features),
+            &Denoiser::default(),
+        );
+        // the columns next to the edge stay on their side of it
+        assert!(error(&guided, SIZE / 2 - 1..SIZE / 2, 0.5) < 0.05);
+        assert!(error(&guided, SIZE / 2..SIZE / 2 + 1, 0.1) < 0.05);
+
+        let no_iterations = Denoiser {
+            iterations: 0,
+            ..Denoiser::default()
+        };
+        assert_eq!(
+            atrous(SIZE, SIZE, &color, &variance, None, &no_iterations),
+            color
+        );
+    }
+}
@@ -0,0 +1,624 @@
+use crate::aov::{AovBuffer, AovPixel, AovSettings};
+use crate::filter::Filter;
+use crate::hdr::FloatImage;
+use crate::scheduler::Tile;
+use crate::tonemap::ToneMapping;
+use crate::utils::*;
+use crate::vec3::{Color, Vec3};
+use image::{ImageBuffer, ImageError, ImageFormat, Rgb, RgbImage};
+use std::fs;
+use std::io::{self, Read, Write};
+
+// Film
+//
+// Accumulates filter-weighted linear radiance and the filter weights per pixel
+// in floating point, together with the running mean and variance of the
+// luminance of the samples taken in each pixel, so that an estimate and its
+// noise can be read out at any time.
+//
+// Splatted contributions are rounded to multiples of 2^-24 first. Sums of such
+// values are exact in an f64 (up to 2^29), so the film comes out bit-identical
+// in whatever order tiles, and the samples within them, are added.
+const SPLAT_SCALE: f64 = (1u64 << 24) as f64;
+
+// below this luminance the relative error is measured against this value, so
+// that near-black pixels don't dominate the average
+const MIN_LUMINANCE: f64 = 1e-3;
+
+// With negative filter lobes the weights of a sparsely sampled pixel can all
+// but cancel, and dividing by what is left would turn a sample into an extreme
+// value. The divisor is kept to at least this fraction of the magnitude of the
+// weights; converged pixels keep about half of it under Lanczos and more under
+// Mitchell, so they are not affected.
+const MIN_WEIGHT_FRACTION: f64 = 0.25;
+
+#[derive(Clone)]
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    sum: Vec<Color>,
+    weight: Vec<f64>,
+    abs_weight: Vec<f64>,
+    stats: Vec<Welford>,
+    aovs: Option<AovBuffer>,
+}
+
+// What a tile adds to the film: the weighted radiance, weights and weight
+// magnitudes its samples splatted over `bounds`, the tile grown by the filter
+// radius and clipped to the image, and the statistics of the samples taken
+// in each of its own pixels, as well as their AOVs if enabled. All are
+// row-major.
+pub struct TileSamples {
+    pub bounds: Tile,
+    pub sum: Vec<Color>,
+    pub weight: Vec<f64>,
+    pub abs_weight: Vec<f64>,
+    pub stats: Vec<Welford>,
+    pub aovs: Option<AovBuffer>,
+}
+
+impl TileSamples {
+    pub fn new(
+        tile: &Tile,
+        filter: &Filter,
+        width: u32,
+        height: u32,
+        aovs: Option<&AovSettings>,
+    ) -> Self {
+        let margin = (filter.radius() - 0.5).max(0.0).ceil() as u32;
+        let bounds = Tile {
+            index: tile.index,
+            x0: tile.x0.saturating_sub(margin),
+            y0: tile.y0.saturating_sub(margin),
+            x1: (tile.x1 + margin).min(width),
+            y1: (tile.y1 + margin).min(height),
+        };
+        let n = (bounds.width() * bounds.height()) as usize;
+        let pixels = (tile.width() * tile.height()) as usize;
+        Self {
+            bounds,
+            sum: vec![Color::zero(); n],
+            weight: vec![0.0; n],
+            abs_weight: vec![0.0; n],
+            stats: vec![Welford::default(); pixels],
+            aovs: aovs.map(|settings| AovBuffer::new(settings, pixels, n)),
+        }
+    }
+
+    // Adds a sample taken at image position (px, py) to every pixel whose
+    // center is within the filter radius. With AOVs `light_groups` is its
+    // radiance split by light group, and is splatted the same way.
+    pub fn splat(
+        &mut self,
+        filter: &Filter,
+        px: f64,
+        py: f64,
+        radiance: Color,
+        light_groups: &[Color],
+    ) {
+        let r = filter.radius();
+        let b = self.bounds;
+        let x0 = (px - 0.5 - r).ceil().max(b.x0 as f64) as i64;
+        let x1 = (px - 0.5 + r).floor().min(b.x1 as f64 - 1.0) as i64;
+        let y0 = (py - 0.5 - r).ceil().max(b.y0 as f64) as i64;
+        let y1 = (py - 0.5 + r).floor().min(b.y1 as f64 - 1.0) as i64;
+
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let w = filter.eval(x as f64 + 0.5 - px, y as f64 + 0.5 - py);
+                if w == 0.0 {
+                    continue;
+                }
+                let k = ((y - b.y0 as i64) * b.width() as i64 + (x - b.x0 as i64)) as usize;
+                let c = radiance * w;
+                self.sum[k] += Color::new(quantize(c.x), quantize(c.y), quantize(c.z));
+                self.weight[k] += quantize(w);
+                self.abs_weight[k] += quantize(w.abs());
+                if let Some(aovs) = &mut self.aovs {
+                    for (g, &c) in light_groups.iter().enumerate() {
+                        let c = c * w;
+                        aovs.light_groups[k * aovs.stride + g] +=
+                            Color::new(quantize(c.x), quantize(c.y), quantize(c.z));
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn quantize(x: f64) -> f64 {
+    (x * SPLAT_SCALE).round() / SPLAT_SCALE
+}
+
+// Welford's online mean and variance of the luminance of a pixel's samples
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Welford {
+    pub n: u32,
+    pub mean: f64,
+    pub m2: f64,
+}
+
+impl Welford {
+    pub fn add(&mut self, x: f64) {
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    // combines the statistics of two disjoint sets of samples (Chan et al.)
+    pub fn merge(&mut self, other: &Welford) {
+        if other.n == 0 {
+            return;
+        }
+        let n = self.n + other.n;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.n as f64 / n as f64;
+        self.m2 += other.m2 + delta * delta * self.n as f64 * other.n as f64 / n as f64;
+        self.n = n;
+    }
+
+    pub fn variance(&self) -> f64 {
+        match self.n {
+            0 | 1 => INF,
+            n => self.m2 / (n - 1) as f64,
+        }
+    }
+
+    // standard error of the mean relative to the mean
+    pub fn relative_error(&self) -> f64 {
+        match self.n {
+            0 | 1 => INF,
+            n => (self.variance() / n as f64).sqrt() / self.mean.max(MIN_LUMINANCE),
+        }
+    }
+}
+
+pub fn luminance(c: Color) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Self {
+        let n = (width * height) as usize;
+        Self {
+            width,
+            height,
+            sum: vec![Color::zero(); n],
+            weight: vec![0.0; n],
+            abs_weight: vec![0.0; n],
+            stats: vec![Welford::default(); n],
+            aovs: None,
+        }
+    }
+
+    pub fn with_aovs(width: u32, height: u32, settings: &AovSettings) -> Self {
+        let n = (width * height) as usize;
+        Self {
+            aovs: Some(AovBuffer::new(settings, n, n)),
+            ..Self::new(width, height)
+        }
+    }
+
+    // a copy of the film whose estimate is `pixels`, row-major, as produced
+    // by post-processing
+    pub fn with_pixels(&self, pixels: Vec<Color>) -> Self {
+        assert_eq!(pixels.len(), self.sum.len());
+        Self {
+            weight: vec![1.0; pixels.len()],
+            abs_weight: vec![1.0; pixels.len()],
+            sum: pixels,
+            ..self.clone()
+        }
+    }
+
+    fn offset(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    pub fn add_tile(&mut self, tile: &Tile, result: &TileSamples) {
+        let bounds = &result.bounds;
+        for (i, y) in (bounds.y0..bounds.y1).enumerate() {
+            for (j, x) in (bounds.x0..bounds.x1).enumerate() {
+                let offset = self.offset(x, y);
+                let k = i * bounds.width() as usize + j;
+                self.sum[offset] += result.sum[k];
+                self.weight[offset] += result.weight[k];
+                self.abs_weight[offset] += result.abs_weight[k];
+                if let (Some(aovs), Some(tile_aovs)) = (&mut self.aovs, &result.aovs) {
+                    let stride = aovs.stride;
+                    for g in 0..stride {
+                        aovs.light_groups[offset * stride + g] +=
+                            tile_aovs.light_groups[k * stride + g];
+                    }
+                }
+            }
+        }
+        for (i, y) in (tile.y0..tile.y1).enumerate() {
+            for (j, x) in (tile.x0..tile.x1).enumerate() {
+                let offset = self.offset(x, y);
+                let k = i * tile.width() as usize + j;
+                self.stats[offset].merge(&result.stats[k]);
+                if let (Some(aovs), Some(tile_aovs)) = (&mut self.aovs, &result.aovs) {
+                    aovs.pixels[offset].merge(&tile_aovs.pixels[k]);
+                }
+            }
+        }
+    }
+
+    pub fn samples(&self, x: u32, y: u32) -> u32 {
+        self.stats[self.offset(x, y)].n
+    }
+
+    pub fn stats(&self, x: u32, y: u32) -> Welford {
+        self.stats[self.offset(x, y)]
+    }
+
+    // what the sums of a pixel are divided by, None until its weights add up
+    // to more than zero
+    fn normalization(&self, offset: usize) -> Option<f64> {
+        let weight = self.weight[offset];
+        if weight > 0.0 {
+            Some(weight.max(MIN_WEIGHT_FRACTION * self.abs_weight[offset]))
+        } else {
+            None
+        }
+    }
+
+    // current estimate of the pixel, black until a sample has been splatted
+    // onto it
+    pub fn pixel(&self, x: u32, y: u32) -> Color {
+        let offset = self.offset(x, y);
+        match self.normalization(offset) {
+            Some(weight) => self.sum[offset] / weight,
+            None => Color::zero(),
+        }
+    }
+
+    pub fn aov(&self, x: u32, y: u32) -> Option<&AovPixel> {
+        let offset = self.offset(x, y);
+        self.aovs.as_ref().map(|aovs| &aovs.pixels[offset])
+    }
+
+    // the radiance of light group `group` in the pixel, the background being
+    // the group after the last light group
+    pub fn light_group(&self, x: u32, y: u32, group: usize) -> Color {
+        let offset = self.offset(x, y);
+        match (&self.aovs, self.normalization(offset)) {
+            (Some(aovs), Some(weight)) => aovs.light_groups[offset * aovs.stride + group] / weight,
+            _ => Color::zero(),
+        }
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.stats.iter().map(|s| s.n as u64).sum()
+    }
+
+    pub fn mean_samples(&self) -> f64 {
+        self.total_samples() as f64 / self.stats.len().max(1) as f64
+    }
+
+    // relative error of the pixel estimates averaged over the image
+    pub fn mean_relative_error(&self) -> f64 {
+        let total: f64 = self.stats.iter().map(|s| s.relative_error()).sum();
+        total / self.stats.len().max(1) as f64
+    }
+
+    pub fn to_rgb_image(&self, tone: &ToneMapping) -> RgbImage {
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            tone.apply(self.pixel(x, y), x, y)
+        })
+    }
+
+    // the linear estimate as R, G, B and an opaque A channel
+    pub fn to_float_image(&self) -> FloatImage {
+        let mut image = FloatImage::new(self.width, self.height);
+        let pixels: Vec<Color> = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.pixel(x, y))
+            .collect();
+        image.add_channel("R", pixels.iter().map(|c| c.x as f32).collect());
+        image.add_channel("G", pixels.iter().map(|c| c.y as f32).collect());
+        image.add_channel("B", pixels.iter().map(|c| c.z as f32).collect());
+        image.add_channel("A", vec![1.0; pixels.len()]);
+        image
+    }
+
+    // The beauty with the AOVs as extra layers, for a multi-layer EXR. The
+    // IDs are stored as floats, which is exact up to 2^24 in a full float
+    // image.
+    pub fn to_aov_image(&self) -> FloatImage {
+        let mut image = self.to_float_image();
+        let aovs = match &self.aovs {
+            Some(aovs) => aovs,
+            None => return image,
+        };
+        let coords: Vec<(u32, u32)> = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .collect();
+        let mut layer = |name: &str, names: &[&str], f: &dyn Fn(u32, u32) -> Vec3| {
+            let values: Vec<Vec3> = coords.iter().map(|&(x, y)| f(x, y)).collect();
+            let components: [fn(&Vec3) -> f64; 3] = [|v| v.x, |v| v.y, |v| v.z];
+            for (suffix, component) in names.iter().zip(components.iter()) {
+                image.add_channel(
+                    &format!("{}.{}", name, suffix),
+                    values.iter().map(|v| component(v) as f32).collect(),
+                );
+            }
+        };
+        let pixel = |x: u32, y: u32| aovs.pixels[self.offset(x, y)];
+
+        layer("normal", &["X", "Y", "Z"], &|x, y| pixel(x, y).normal());
+        layer("albedo", &["R", "G", "B"], &|x, y| pixel(x, y).albedo());
+        layer("position", &["X", "Y", "Z"], &|x, y| pixel(x, y).position());
+        layer("depth", &["Z"], &|x, y| {
+            Vec3::new(pixel(x, y).depth(), 0.0, 0.0)
+        });
+        layer("objectId", &["id"], &|x, y| {
+            Vec3::new(pixel(x, y).object_id as f64, 0.0, 0.0)
+        });
+        layer("materialId", &["id"], &|x, y| {
+            Vec3::new(pixel(x, y).material_id as f64, 0.0, 0.0)
+        });
+        for group in 0..aovs.stride {
+            let name = if group + 1 == aovs.stride {
+                "background".to_owned()
+            } else {
+                format!("lightgroup{}", group)
+            };
+            layer(&name, &["R", "G", "B"], &|x, y| {
+                self.light_group(x, y, group)
+            });
+        }
+
+        image
+    }
+
+    // samples taken per pixel, from black (none) through red and yellow to
+    // white (the most of any pixel)
+    pub fn to_heatmap(&self) -> RgbImage {
+        let max = self.stats.iter().map(|s| s.n).max().unwrap_or(0).max(1);
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            heat_color(self.samples(x, y) as f64 / max as f64)
+        })
+    }
+
+    // writes the current estimate as a PNG, see `save_png`
+    pub fn save_snapshot(&self, path: &str, tone: &ToneMapping) -> io::Result<()> {
+        save_png(&self.to_rgb_image(tone), path)
+    }
+}
+
+// The image goes to a temporary file first and is then renamed over `path`, so
+// whatever is at `path` is always a complete image even if the process is
+// killed mid-write.
+pub fn save_png(image: &RgbImage, path: &str) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    image
+        .save_with_format(&tmp_path, ImageFormat::Png)
+        .map_err(|e| match e {
+            ImageError::IoError(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        })?;
+    fs::rename(&tmp_path, path)
+}
+
+// raw little-endian dump of the accumulated state, used by checkpoints
+impl Film {
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.width.to_le_bytes())?;
+        out.write_all(&self.height.to_le_bytes())?;
+        for i in 0..self.sum.len() {
+            let (sum, stats) = (self.sum[i], self.stats[i]);
+            out.write_all(&sum.x.to_le_bytes())?;
+            out.write_all(&sum.y.to_le_bytes())?;
+            out.write_all(&sum.z.to_le_bytes())?;
+            out.write_all(&self.weight[i].to_le_bytes())?;
+            out.write_all(&self.abs_weight[i].to_le_bytes())?;
+            out.write_all(&stats.n.to_le_bytes())?;
+            out.write_all(&stats.mean.to_le_bytes())?;
+            out.write_all(&stats.m2.to_le_bytes())?;
+        }
+        match &self.aovs {
+            Some(aovs) => {
+                out.write_all(&[1])?;
+                aovs.write(out)?;
+            }
+            None => out.write_all(&[0])?,
+        }
+
+        Ok(())
+    }
+
+    pub fn read(input: &mut impl Read) -> io::Result<Self> {
+        let width = read_u32(input)?;
+        let height = read_u32(input)?;
+        let mut film = Film::new(width, height);
+        for i in 0..film.sum.len() {
+            film.sum[i] = Color::new(read_f64(input)?, read_f64(input)?, read_f64(input)?);
+            film.weight[i] = read_f64(input)?;
+            film.abs_weight[i] = read_f64(input)?;
+            film.stats[i] = Welford {
+                n: read_u32(input)?,
+                mean: read_f64(input)?,
+                m2: read_f64(input)?,
+            };
+        }
+        let mut has_aovs = [0u8; 1];
+        input.read_exact(&mut has_aovs)?;
+        if has_aovs[0] != 0 {
+            film.aovs = Some(AovBuffer::read(input, film.sum.len())?);
+        }
+
+        Ok(film)
+    }
+}
+
+pub fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub fn read_f64(input: &mut impl Read) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(input)?))
+}
+
+fn heat_color(t: f64) -> Rgb<u8> {
+    let channel = |c: f64| (clamp(c, 0.0, 0.999) * 256.0) as u8;
+    Rgb([
+        channel(3.0 * t),
+        channel(3.0 * t - 1.0),
+        channel(3.0 * t - 2.0),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_film_normalizes_by_weight() {
+        let tile = Tile {
+            index: 0,
+            x0: 1,
+            y0: 0,
+            x1: 3,
+            y1: 1,
+        };
+        let mut film = Film::new(4, 2);
+        let stats = |n| Welford {
+            n,
+            ..Welford::default()
+        };
+        film.add_tile(
+            &tile,
+            &TileSamples {
+                bounds: tile,
+                sum: vec![Color::new(2.0, 4.0, 6.0), Color::ones()],
+                weight: vec![2.0, 2.0],
+                abs_weight: vec![2.0, 2.0],
+                stats: vec![stats(2), stats(2)],
+                aovs: None,
+            },
+        );
+        film.add_tile(
+            &tile,
+            &TileSamples {
+                bounds: tile,
+                sum: vec![Color::new(1.0, 2.0, 3.0), Color::ones()],
+                weight: vec![1.0, 1.0],
+                abs_weight: vec![1.0, 1.0],
+                stats: vec![stats(1), stats(1)],
+                aovs: None,
+            },
+        );
+
+        assert_eq!(film.pixel(1, 0), Color::new(1.0, 2.0, 3.0));
+        assert_eq!(film.samples(1, 0), 3);
+        assert_eq!(film.pixel(0, 0), Color::zero());
+        assert_eq!(film.samples(0, 1), 0);
+    }
+
+    #[test]
+    fn test_splat_reconstructs_constant() {
+        let filters = [
+            Filter::Box { radius: 0.5 },
+            Filter::Tent { radius: 1.5 },
+            Filter::Gaussian {
+                radius: 1.5,
+                sigma: 0.5,
+            },
+            Filter::Mitchell {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            },
+            Filter::Lanczos { radius: 2.0 },
+        ];
+        let tile = Tile {
+            index: 0,
+            x0: 2,
+            y0: 2,
+            x1: 6,
+            y1: 6,
+        };
+        let c = Color::new(0.25, 0.5, 3.0);
+        for filter in filters.iter() {
+            let mut result = TileSamples::new(&tile, filter, 8, 8, None);
+            for y in tile.y0..tile.y1 {
+                for x in tile.x0..tile.x1 {
+                    for i in 0..16 {
+                        let (jx, jy) = ((i % 4) as f64 + 0.5, (i / 4) as f64 + 0.5);
+                        result.splat(filter, x as f64 + jx / 4.0, y as f64 + jy / 4.0, c, &[]);
+                    }
+                }
+            }
+            let mut film = Film::new(8, 8);
+            film.add_tile(&tile, &result);
+
+            let p = film.pixel(3, 4);
+            assert!((p - c).length() < 1e-6, "{:?}: {:?}", filter, p);
+        }
+    }
+
+    #[test]
+    fn test_sparse_lanczos_pixel_stays_bounded() {
+        let filter = Filter::Lanczos { radius: 3.0 };
+        let tile = Tile {
+            index: 0,
+            x0: 0,
+            y0: 0,
+            x1: 8,
+            y1: 1,
+        };
+        let mut film = Film::new(8, 1);
+
+        // a lone sample on an outer positive lobe of pixel 0 keeps its value
+        let mut result = TileSamples::new(&tile, &filter, 8, 1, None);
+        result.splat(&filter, 3.0, 0.5, Color::ones(), &[]);
+        film.add_tile(&tile, &result);
+        assert!((film.pixel(0, 0) - Color::ones()).length() < 1e-3);
+
+        // a bright sample nearby and dark ones on the negative lobe leave
+        // almost no weight, the estimate stays within reach of the samples
+        let mut result = TileSamples::new(&tile, &filter, 8, 1, None);
+        result.splat(&filter, 0.8, 0.5, Color::ones() * 100.0, &[]);
+        for _ in 0..6 {
+            result.splat(&filter, 2.0, 0.5, Color::zero(), &[]);
+        }
+        let mut film = Film::new(8, 1);
+        film.add_tile(&tile, &result);
+        let p = film.pixel(0, 0);
+        assert!(p.x > 0.0 && p.x <= 100.0 / MIN_WEIGHT_FRACTION, "{:?}", p);
+    }
+
+    #[test]
+    fn test_welford_merge() {
+        let xs = [0.5, 1.5, 0.25, 3.0, 2.0, 0.75, 1.0];
+        let mut all = Welford::default();
+        let mut a = Welford::default();
+        let mut b = Welford::default();
+        for (i, &x) in xs.iter().enumerate() {
+            all.add(x);
+            if i < 3 {
+                a.add(x);
+            } else {
+                b.add(x);
+            }
+        }
+        a.merge(&b);
+
+        let mean = xs.iter().sum::<f64>() / 7.0;
+        let var = xs.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / 6.0;
+        assert_eq!(a.n, 7);
+        assert!((a.mean - mean).abs() < 1e-12 && (all.mean - mean).abs() < 1e-12);
+        assert!((a.variance() - var).abs() < 1e-12 && (all.variance() - var).abs() < 1e-12);
+    }
+}
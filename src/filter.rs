@@ -0,0 +1,134 @@
+use crate::utils::*;
+
+// Reconstruction filters
+//
+// Every sample is splatted onto the pixels whose centers lie within `radius`
+// of it, weighted by the filter, and a pixel's value is its weighted sum of
+// radiance over its sum of weights. All filters are separable products of a
+// 1D filter in x and in y.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Box { radius: f64 },
+    Tent { radius: f64 },
+    Gaussian { radius: f64, sigma: f64 },
+    // B = C = 1/3 is the usual choice, it has small negative lobes
+    Mitchell { radius: f64, b: f64, c: f64 },
+    // windowed sinc with `radius` lobes
+    Lanczos { radius: f64 },
+}
+
+impl Default for Filter {
+    // every sample only counts for the pixel it was taken in
+    fn default() -> Self {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl Filter {
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    // weight of a sample at offset (dx, dy) from a pixel center
+    pub fn eval(&self, dx: f64, dy: f64) -> f64 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+
+    fn eval_1d(&self, d: f64) -> f64 {
+        let d = d.abs();
+        match *self {
+            Filter::Box { radius } => {
+                if d < radius {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Tent { radius } => (radius - d).max(0.0),
+            Filter::Gaussian { radius, sigma } => {
+                let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+                // shifted down so that it reaches zero at the radius
+                (gaussian(d) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell { radius, b, c } => {
+                let x = 2.0 * d / radius;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                } else if x < 2.0 {
+                    ((-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Lanczos { radius } => {
+                if d < radius {
+                    sinc(d) * sinc(d / radius)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters() -> Vec<Filter> {
+        vec![
+            Filter::Box { radius: 0.5 },
+            Filter::Tent { radius: 1.0 },
+            Filter::Gaussian {
+                radius: 1.5,
+                sigma: 0.5,
+            },
+            Filter::Mitchell {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            },
+            Filter::Lanczos { radius: 3.0 },
+        ]
+    }
+
+    #[test]
+    fn test_filter_support() {
+        for filter in filters() {
+            let r = filter.radius();
+            assert!(filter.eval(0.0, 0.0) > 0.0);
+            assert_eq!(filter.eval(r, 0.0), 0.0);
+            assert_eq!(filter.eval(0.0, -r - 0.1), 0.0);
+            assert_eq!(filter.eval(0.3, -0.2), filter.eval(-0.3, 0.2));
+        }
+    }
+
+    #[test]
+    fn test_mitchell_has_negative_lobes() {
+        let mitchell = filters()[3];
+        assert!(mitchell.eval(1.5, 0.0) < 0.0);
+        assert!(filters()[4].eval(1.5, 0.0) < 0.0);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::film::{self, Film};
+use crate::vec3::Color;
+
+// Fireflies
+//
+// Rare paths that carry a lot of energy, like caustics through glass from a
+// bright light, show up as isolated white pixels long after the rest of the
+// image has converged. Clamping caps what a single sample may contribute,
+// which removes most of them at the cost of some energy. Outlier rejection
+// then repairs the pixels that still stand far above all their neighbours,
+// unless the pixel's own samples agree on it: a small light seen straight
+// from the camera is bright in every sample, a firefly only in a few.
+
+// Both limits apply to the largest component of a sample's radiance, which is
+// scaled down to keep its hue. Emitters seen straight from the camera are
+// never clamped.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Clamping {
+    // light reaching the first surface straight from an emitter or the sky
+    pub max_direct: Option<f64>,
+    // light that took two bounces or more
+    pub max_indirect: Option<f64>,
+}
+
+impl Clamping {
+    // the part of a sample's radiance that reached the camera from a light
+    // found after `bounce` bounces
+    pub fn apply(&self, radiance: Color, bounce: u32) -> Color {
+        let max = match bounce {
+            0 => None,
+            1 => self.max_direct,
+            _ => self.max_indirect,
+        };
+        let largest = radiance.x.max(radiance.y).max(radiance.z);
+        match max {
+            Some(max) if largest > max => radiance * (max / largest),
+            _ => radiance,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutlierRejection {
+    // how many standard deviations above the mean luminance of its neighbours
+    // a pixel may be before it is replaced by their mean
+    pub threshold: f64,
+}
+
+// Neighbourhoods that are almost uniform get at least this much spread,
+// relative to their mean, so that plain noise isn't mistaken for outliers.
+const MIN_SPREAD: f64 = 0.25;
+
+// Pixels whose mean is known at least this well, relative to itself, are
+// kept. A pixel brightened by a single sample out of many has a relative
+// error of about 1, by k of them about 1 / sqrt(k).
+const MAX_KEPT_ERROR: f64 = 0.5;
+
+// a copy of the film with the outliers replaced
+pub fn reject_outliers(film: &Film, rejection: &OutlierRejection) -> Film {
+    let (width, height) = (film.width as i64, film.height as i64);
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let c = film.pixel(x as u32, y as u32);
+            let mut neighbours: Vec<Color> = Vec::with_capacity(8);
+            for ny in (y - 1).max(0)..(y + 2).min(height) {
+                for nx in (x - 1).max(0)..(x + 2).min(width) {
+                    if (nx, ny) != (x, y) {
+                        neighbours.push(film.pixel(nx as u32, ny as u32));
+                    }
+                }
+            }
+
+            let n = neighbours.len() as f64;
+            let mean = neighbours.iter().fold(Color::zero(), |acc, &c| acc + c) / n;
+            let lum = film::luminance(mean);
+            let variance = neighbours
+                .iter()
+                .map(|&c| (film::luminance(c) - lum).powi(2))
+                .sum::<f64>()
+                / n;
+            let spread = variance.sqrt().max(MIN_SPREAD * lum).max(1e-3);
+            let outlier = film::luminance(c) > lum + rejection.threshold * spread;
+            if outlier && film.stats(x as u32, y as u32).relative_error() > MAX_KEPT_ERROR {
+                pixels.push(mean);
+            } else {
+                pixels.push(c);
+            }
+        }
+    }
+
+    film.with_pixels(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::film::{TileSamples, Welford};
+    use crate::filter::Filter;
+    use crate::scheduler::Tile;
+
+    #[test]
+    fn test_clamping() {
+        let clamping = Clamping {
+            max_direct: Some(10.0),
+            max_indirect: Some(1.0),
+        };
+        let c = Color::new(20.0, 10.0, 5.0);
+        assert_eq!(clamping.apply(c, 0), c);
+        assert_eq!(clamping.apply(c, 1), Color::new(10.0, 5.0, 2.5));
+        assert_eq!(clamping.apply(c, 3), Color::new(1.0, 0.5, 0.25));
+        assert_eq!(clamping.apply(c * 0.01, 3), c * 0.01);
+        assert_eq!(Clamping::default().apply(c, 3), c);
+    }
+
+    // a black film with one bright pixel, made of `hot` samples of 50 out of 16
+    fn lone_bright_pixel(hot: u32) -> Film {
+        let tile = Tile {
+            index: 0,
+            x0: 0,
+            y0: 0,
+            x1: 5,
+            y1: 5,
+        };
+        let filter = Filter::default();
+        let mut result = TileSamples::new(&tile, &filter, 5, 5, None);
+        let mut stats = Welford::default();
+        for i in 0..16 {
+            let c = if i < hot {
+                Color::ones() * 50.0
+            } else {
+                Color::zero()
+            };
+            result.splat(&filter, 2.5, 2.5, c, &[]);
+            stats.add(film::luminance(c));
+        }
+        result.stats[12] = stats;
+        for y in 0..5 {
+            for x in 0..5 {
+                if (x, y) != (2, 2) {
+                    result.splat(&filter, x as f64 + 0.5, y as f64 + 0.5, Color::zero(), &[]);
+                }
+            }
+        }
+        let mut film = Film::new(5, 5);
+        film.add_tile(&tile, &result);
+        film
+    }
+
+    #[test]
+    fn test_emitters_are_kept() {
+        let rejection = OutlierRejection { threshold: 4.0 };
+        let light = reject_outliers(&lone_bright_pixel(16), &rejection);
+        assert_eq!(light.pixel(2, 2), Color::ones() * 50.0);
+        let firefly = reject_outliers(&lone_bright_pixel(1), &rejection);
+        assert_eq!(firefly.pixel(2, 2), Color::zero());
+    }
+
+    #[test]
+    fn test_reject_outliers() {
+        // a noisy gray image with one firefly and one bright but wide edge
+        let (width, height) = (8, 8);
+        let pixels: Vec<Color> = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                match (x, y) {
+                    (2, 3) => Color::ones() * 50.0,
+                    (6..=7, _) => Color::ones() * 4.0,
+                    _ => Color::ones() * (0.5 + 0.05 * ((x * 7 + y * 3) % 5) as f64),
+                }
+            })
+            .collect();
+        let film = Film::new(width, height).with_pixels(pixels);
+        let result = reject_outliers(&film, &OutlierRejection { threshold: 4.0 });
+
+        assert!(result.pixel(2, 3).y < 1.0);
+        for y in 0..height {
+            for x in 0..width {
+                if (x, y) != (2, 3) {
+                    assert_eq!(result.pixel(x, y), film.pixel(x, y));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// HDR images
+//
+// A linear f32 image with any number of named channels, which can be written
+// as OpenEXR with every channel, or as Radiance RGBE and PFM from its R, G and
+// B channels. Unlike PNG none of these clamp, so bright emitters keep their
+// intensity for grading.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HdrFormat {
+    ExrHalf,
+    ExrFloat,
+    Radiance,
+    Pfm,
+}
+
+impl HdrFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            HdrFormat::ExrHalf | HdrFormat::ExrFloat => "exr",
+            HdrFormat::Radiance => "hdr",
+            HdrFormat::Pfm => "pfm",
+        }
+    }
+}
+
+pub struct Channel {
+    pub name: String,
+    pub data: Vec<f32>, // row-major, top row first
+}
+
+pub struct FloatImage {
+    pub width: u32,
+    pub height: u32,
+    pub channels: Vec<Channel>,
+}
+
+impl FloatImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn add_channel(&mut self, name: &str, data: Vec<f32>) {
+        assert_eq!(data.len(), (self.width * self.height) as usize);
+        self.channels.push(Channel {
+            name: name.to_owned(),
+            data,
+        });
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&[f32]> {
+        self.channels
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.data.as_slice())
+    }
+
+    pub fn save(&self, path: &str, format: HdrFormat) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        match format {
+            HdrFormat::ExrHalf => self.write_exr(&mut out, true)?,
+            HdrFormat::ExrFloat => self.write_exr(&mut out, false)?,
+            HdrFormat::Radiance => self.write_radiance(&mut out)?,
+            HdrFormat::Pfm => self.write_pfm(&mut out)?,
+        }
+        out.flush()
+    }
+
+    fn rgb(&self) -> io::Result<[&[f32]; 3]> {
+        match (self.channel("R"), self.channel("G"), self.channel("B")) {
+            (Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the image has no R, G and B channels",
+            )),
+        }
+    }
+
+    // Scanline OpenEXR without compression, one scanline per block. The
+    // channels are stored in alphabetical order as the format requires.
+    pub fn write_exr(&self, out: &mut impl Write, half: bool) -> io::Result<()> {
+        let mut channels: Vec<&Channel> = self.channels.iter().collect();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+        let (pixel_type, size): (i32, usize) = if half { (1, 2) } else { (2, 4) };
+
+        let mut header: Vec<u8> = Vec::new();
+        header.extend_from_slice(&20_000_630i32.to_le_bytes());
+        header.extend_from_slice(&2i32.to_le_bytes());
+
+        let mut chlist: Vec<u8> = Vec::new();
+        for channel in &channels {
+            chlist.extend_from_slice(channel.name.as_bytes());
+            chlist.push(0);
+            chlist.extend_from_slice(&pixel_type.to_le_bytes());
+            chlist.extend_from_slice(&[0, 0, 0, 0]); // pLinear and reserved
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+        }
+        chlist.push(0);
+        let window: Vec<u8> = [0, 0, self.width as i32 - 1, self.height as i32 - 1]
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+
+        let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+            header.extend_from_slice(name.as_bytes());
+            header.push(0);
+            header.extend_from_slice(kind.as_bytes());
+            header.push(0);
+            header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            header.extend_from_slice(value);
+        };
+        attribute("channels", "chlist", &chlist);
+        attribute("compression", "compression", &[0]);
+        attribute("dataWindow", "box2i", &window);
+        attribute("displayWindow", "box2i", &window);
+        attribute("lineOrder", "lineOrder", &[0]);
+        attribute("pixelAspectRatio", "float", &1f32.to_le_bytes());
+        attribute("screenWindowCenter", "v2f", &[0; 8]);
+        attribute("screenWindowWidth", "float", &1f32.to_le_bytes());
+        header.push(0);
+
+        // the offset table points at every scanline block
+        let width = self.width as usize;
+        let block_size = 8 + width * channels.len() * size;
+        let first_block = header.len() + 8 * self.height as usize;
+        out.write_all(&header)?;
+        for y in 0..self.height as usize {
+            out.write_all(&((first_block + y * block_size) as u64).to_le_bytes())?;
+        }
+
+        for y in 0..self.height as usize {
+            out.write_all(&(y as i32).to_le_bytes())?;
+            out.write_all(&((block_size - 8) as i32).to_le_bytes())?;
+            for channel in &channels {
+                for &v in &channel.data[y * width..(y + 1) * width] {
+                    if half {
+                        out.write_all(&f32_to_half(v).to_le_bytes())?;
+                    } else {
+                        out.write_all(&v.to_le_bytes())?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Radiance RGBE with flat (not run-length encoded) scanlines
+    pub fn write_radiance(&self, out: &mut impl Write) -> io::Result<()> {
+        let [r, g, b] = self.rgb()?;
+        write!(
+            out,
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            self.height, self.width
+        )?;
+        for i in 0..r.len() {
+            out.write_all(&to_rgbe(r[i], g[i], b[i]))?;
+        }
+
+        Ok(())
+    }
+
+    // little-endian PFM, which stores the bottom row first
+    pub fn write_pfm(&self, out: &mut impl Write) -> io::Result<()> {
+        let [r, g, b] = self.rgb()?;
+        write!(out, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        let width = self.width as usize;
+        for y in (0..self.height as usize).rev() {
+            for i in y * width..(y + 1) * width {
+                out.write_all(&r[i].to_le_bytes())?;
+                out.write_all(&g[i].to_le_bytes())?;
+                out.write_all(&b[i].to_le_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// IEEE 754 half precision, rounding to nearest even
+pub fn f32_to_half(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x7f_ffff;
+
+    if exp == 0xff {
+        // infinity stays infinity, NaN stays NaN
+        return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if e <= 0 {
+        // subnormal, or too small and flushed to zero
+        if e < -10 {
+            return sign;
+        }
+        let m = mant | 0x80_0000;
+        let shift = (14 - e) as u32;
+        return sign | round_shift(m, shift) as u16;
+    }
+
+    // a carry out of the mantissa correctly bumps the exponent
+    sign | round_shift(((e as u32) << 23) | mant, 13) as u16
+}
+
+fn round_shift(x: u32, shift: u32) -> u32 {
+    let kept = x >> shift;
+    let rest = x & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    if rest > halfway || (rest == halfway && kept & 1 == 1) {
+        kept + 1
+    } else {
+        kept
+    }
+}
+
+// shared exponent encoding: the largest component sets the exponent
+fn to_rgbe(r: f32, g: f32, b: f32) -> [u8; 4] {
+    let v = r.max(g).max(b);
+    if v < 1e-32 {
+        return [0; 4];
+    }
+    let e = v.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2f32.powi(e);
+    let channel = |c: f32| (c.max(0.0) * scale).min(255.0) as u8;
+    [channel(r), channel(g), channel(b), (e + 128) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f32_to_half() {
+        assert_eq!(f32_to_half(0.0), 0x0000);
+        assert_eq!(f32_to_half(-0.0), 0x8000);
+        assert_eq!(f32_to_half(1.0), 0x3c00);
+        assert_eq!(f32_to_half(-2.0), 0xc000);
+        assert_eq!(f32_to_half(0.5), 0x3800);
+        assert_eq!(f32_to_half(65504.0), 0x7bff);
+        assert_eq!(f32_to_half(1e6), 0x7c00);
+        assert_eq!(f32_to_half(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_half(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_half(2f32.powi(-14)), 0x0400);
+        assert_eq!(f32_to_half(1e-9), 0x0000);
+        // 1 + 2^-11 is halfway between 1 and the next half, rounds to even
+        assert_eq!(f32_to_half(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_half(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        assert!(f32_to_half(f32::NAN) & 0x3ff != 0);
+    }
+
+    #[test]
+    fn test_rgbe() {
+        for &(r, g, b) in &[(1.0, 0.5, 0.25), (7.0, 3.5, 0.0), (0.01, 0.02, 0.03)] {
+            let [er, eg, eb, e] = to_rgbe(r, g, b);
+            let decode = |c: u8| (c as f32 + 0.5) * 2f32.powi(e as i32 - 136);
+            let max = r.max(g).max(b);
+            for &(c, ec) in &[(r, er), (g, eg), (b, eb)] {
+                assert!((decode(ec) - c).abs() <= max / 128.0);
+            }
+        }
+        assert_eq!(to_rgbe(0.0, 0.0, 0.0), [0; 4]);
+    }
+
+    fn image() -> FloatImage {
+        let mut image = FloatImage::new(3, 2);
+        for &name in &["R", "G", "B", "A", "normal.X"] {
+            image.add_channel(name, (0..6).map(|i| i as f32 * 2.5).collect());
+        }
+        image
+    }
+
+    #[test]
+    fn test_exr_layout() {
+        for &half in &[true, false] {
+            let mut out: Vec<u8> = Vec::new();
+            image().write_exr(&mut out, half).unwrap();
+            assert_eq!(&out[..4], &[0x76, 0x2f, 0x31, 0x01]);
+
+            // the offset table follows the header and points at the blocks,
+            // each tagged with its scanline
+            let block = 8 + 3 * 5 * if half { 2 } else { 4 };
+            let offset = |y: usize| {
+                let start = out.len() - 2 * block - 16 + 8 * y;
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&out[start..start + 8]);
+                u64::from_le_bytes(bytes) as usize
+            };
+            for y in 0..2 {
+                let o = offset(y);
+                assert_eq!(o, out.len() - (2 - y) * block);
+                assert_eq!(&out[o..o + 4], &(y as i32).to_le_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn test_pfm_is_bottom_up() {
+        let mut out: Vec<u8> = Vec::new();
+        image().write_pfm(&mut out).unwrap();
+        let header = b"PF\n3 2\n-1.0\n";
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(out.len(), header.len() + 6 * 3 * 4);
+        // the first pixel written is the first one of the bottom row
+        assert_eq!(&out[header.len()..header.len() + 4], &7.5f32.to_le_bytes());
+    }
+}
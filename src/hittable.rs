@@ -15,6 +15,9 @@ pub struct HitRecord {
     pub u: f64,
     pub v: f64,
     pub front_face: bool, // have the normals always point against the ray
+    // set by `Tagged` for the ID AOVs, 0 when untagged
+    pub object_id: u32,
+    pub material_id: u32,
 }
 
 impl HitRecord {
@@ -27,6 +30,8 @@ impl HitRecord {
             u: 0.0,
             v: 0.0,
             front_face: false,
+            object_id: 0,
+            material_id: 0,
         }
     }
 
@@ -62,6 +67,13 @@ impl HittableList {
     pub fn add(&mut self, object: Arc<dyn Hittable>) {
         self.objects.push(object);
     }
+
+    // gives each object the object ID of its position in the list, from 1
+    pub fn tag_objects(&mut self) {
+        for (i, object) in self.objects.iter_mut().enumerate() {
+            *object = Arc::new(Tagged::object(object.clone(), i as u32 + 1));
+        }
+    }
 }
 
 impl Default for HittableList {
@@ -102,9 +114,9 @@ impl Hittable for HittableList {
                 return false;
             }
             *output_box = if first_box {
-                temp_box.clone()
+                temp_box
             } else {
-                aabb::surrounding_box(&output_box, &temp_box)
+                aabb::surrounding_box(output_box, &temp_box)
             };
             first_box = false;
         }
@@ -148,11 +160,11 @@ impl RotateY {
                     let tester: Vec3 = Vec3::new(newx, y, newz);
 
                     min.x = fmin(min.x, tester.x);
-                    max.x = fmin(max.x, tester.x);
+                    max.x = fmax(max.x, tester.x);
                     min.y = fmin(min.y, tester.y);
-                    max.y = fmin(max.y, tester.y);
+                    max.y = fmax(max.y, tester.y);
                     min.z = fmin(min.z, tester.z);
-                    max.z = fmin(max.z, tester.z);
+                    max.z = fmax(max.z, tester.z);
                 }
             }
         }
@@ -198,8 +210,63 @@ impl Hittable for RotateY {
     }
 
     fn bounding_box(&self, output_box: &mut AABB) -> bool {
-        *output_box = self.bbox.clone();
+        *output_box = self.bbox;
 
         self.hasbox
     }
 }
+
+// Tagged
+//
+// Sets the object or material ID of the hits on the wrapped object. Tags
+// nearer the top of the scene override those further down, and hits on
+// anything untagged below a tag get ID 0.
+
+pub struct Tagged {
+    pub ptr: Arc<dyn Hittable>,
+    pub object_id: Option<u32>,
+    pub material_id: Option<u32>,
+}
+
+impl Tagged {
+    pub fn object(ptr: Arc<dyn Hittable>, id: u32) -> Self {
+        Self {
+            ptr,
+            object_id: Some(id),
+            material_id: None,
+        }
+    }
+
+    pub fn material(ptr: Arc<dyn Hittable>, id: u32) -> Self {
+        Self {
+            ptr,
+            object_id: None,
+            material_id: Some(id),
+        }
+    }
+}
+
+impl Hittable for Tagged {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let ids = (rec.object_id, rec.material_id);
+        rec.object_id = 0;
+        rec.material_id = 0;
+        if !self.ptr.hit(r, t_min, t_max, rec) {
+            rec.object_id = ids.0;
+            rec.material_id = ids.1;
+            return false;
+        }
+        if let Some(id) = self.object_id {
+            rec.object_id = id;
+        }
+        if let Some(id) = self.material_id {
+            rec.material_id = id;
+        }
+
+        true
+    }
+
+    fn bounding_box(&self, output_box: &mut AABB) -> bool {
+        self.ptr.bounding_box(output_box)
+    }
+}
@@ -0,0 +1,76 @@
+use crate::aabb::AABB;
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::transform::Transform;
+use crate::vec3::{Point3, Vec3};
+use std::sync::Arc;
+
+// Instance
+//
+// A transformed reference to shared geometry. The geometry is usually a
+// LinearBvh built once per unique mesh or shape (the bottom level), and a
+// LinearBvh over the instances forms the top level, so thousands of copies of
+// one object only store their transform and an optional material.
+
+pub struct Instance {
+    pub geometry: Arc<dyn Hittable>,
+    pub transform: Transform,
+    pub mat_override: Option<Arc<dyn Material>>,
+    pub hasbox: bool,
+    pub bbox: AABB,
+}
+
+impl Instance {
+    pub fn new(
+        geometry: Arc<dyn Hittable>,
+        transform: Transform,
+        mat_override: Option<Arc<dyn Material>>,
+    ) -> Self {
+        let mut bbox = AABB::new(Point3::zero(), Point3::zero());
+        let hasbox: bool = geometry.bounding_box(&mut bbox);
+        if hasbox {
+            bbox = transform.bbox(&bbox);
+        }
+
+        Self {
+            geometry,
+            transform,
+            mat_override,
+            hasbox,
+            bbox,
+        }
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        // the object space direction is not normalized, so t stays the same
+        let local_r = Ray::new(
+            self.transform.inv_point(r.origin),
+            self.transform.inv_vector(r.direction),
+        );
+        if !self.geometry.hit(local_r, t_min, t_max, rec) {
+            return false;
+        }
+
+        let outward_normal: Vec3 = if rec.front_face {
+            rec.normal
+        } else {
+            -rec.normal
+        };
+        rec.p = r.at(rec.t);
+        rec.set_face_normal(r, self.transform.normal(outward_normal).unit());
+        if let Some(mat) = &self.mat_override {
+            rec.mat_ptr = mat.clone();
+        }
+
+        true
+    }
+
+    fn bounding_box(&self, output_box: &mut AABB) -> bool {
+        *output_box = self.bbox;
+
+        self.hasbox
+    }
+}
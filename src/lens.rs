@@ -0,0 +1,212 @@
+use crate::utils::*;
+use crate::vec3;
+use image::{GrayImage, ImageError};
+use std::io;
+use std::sync::Arc;
+
+// Lens
+//
+// What a thin lens adds to the pinhole camera, besides its size and focus.
+// The aperture sets the shape of out of focus highlights: a disk, a polygon
+// left by the blades of the iris, or any grayscale image. An anamorphic lens
+// squeezes it into an upright oval. Towards the edges of the frame the lens
+// barrel cuts into the aperture, leaving cat's eye shaped highlights and
+// darker corners. Tilting the lens tilts the plane in focus, so a receding
+// table top can be sharp from front to back, and shifting it moves the frame
+// without turning the camera, which keeps the verticals of a tall building
+// parallel.
+
+#[derive(Clone)]
+pub enum Aperture {
+    Circle,
+    // a regular polygon turned by `rotation` degrees
+    Polygon { blades: u32, rotation: f64 },
+    Image(Arc<BokehImage>),
+}
+
+#[derive(Clone)]
+pub struct Lens {
+    pub aperture: Aperture,
+    // how much taller than wide the aperture is, 1 for spherical lenses and
+    // e.g. 2 for a 2x anamorphic
+    pub anamorphic: f64,
+    // how far the barrel's opening moves off the aperture in the corners of
+    // the frame, in aperture radii; 0 turns it off, at 1 the corners keep
+    // about 40% of the aperture
+    pub cats_eye: f64,
+    // angles in degrees between the plane in focus and the image plane, around
+    // the camera's right and up axes; positive pitch brings the focus nearer
+    // along up, positive yaw nearer along right
+    pub tilt: (f64, f64),
+    // how far the frame moves along right and up, in image widths and heights
+    pub shift: (f64, f64),
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            aperture: Aperture::Circle,
+            anamorphic: 1.0,
+            cats_eye: 0.0,
+            tilt: (0.0, 0.0),
+            shift: (0.0, 0.0),
+        }
+    }
+}
+
+impl Lens {
+    // A point on the aperture in units of its radius, or None if the barrel
+    // blocks it as seen from `(x, y)` on the image, which runs from -1 to 1
+    // from the center to the corners.
+    pub fn sample(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+        let (a, b) = match &self.aperture {
+            Aperture::Circle => {
+                let p = vec3::random_in_unit_disk();
+                (p.x, p.y)
+            }
+            Aperture::Polygon { blades, rotation } => {
+                let (u, v) = random_2d();
+                polygon(*blades, degrees_to_radians(*rotation), u, v)
+            }
+            Aperture::Image(image) => {
+                let (u, v) = random_2d();
+                image.sample(u, v)
+            }
+        };
+
+        if self.cats_eye > 0.0 {
+            let (dx, dy) = (a - x * self.cats_eye, b - y * self.cats_eye);
+            if dx * dx + dy * dy > 1.0 {
+                return None;
+            }
+        }
+
+        Some((a / self.anamorphic, b))
+    }
+}
+
+// uniform over the polygon inscribed in the unit circle, u picks one of the
+// triangles around its center and is reused within it
+fn polygon(blades: u32, rotation: f64, u: f64, v: f64) -> (f64, f64) {
+    let n = blades.max(3);
+    let scaled = u * n as f64;
+    let i = (scaled as u32).min(n - 1);
+    let u = scaled - i as f64;
+
+    let angle = |k: u32| rotation + 2.0 * PI * k as f64 / n as f64;
+    let (a, b) = (angle(i), angle(i + 1));
+    let r = u.sqrt();
+    (
+        r * ((1.0 - v) * a.cos() + v * b.cos()),
+        r * ((1.0 - v) * a.sin() + v * b.sin()),
+    )
+}
+
+// A grayscale aperture: how bright a pixel is sets how likely the lens is hit
+// there. The longer side of the image spans the aperture's diameter, and the
+// image is seen upright in highlights behind the plane in focus.
+pub struct BokehImage {
+    width: usize,
+    height: usize,
+    // cumulative distribution of the rows, then of the pixels within each row
+    rows: Vec<f64>,
+    columns: Vec<f64>,
+}
+
+impl BokehImage {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let image = image::open(path).map_err(|e| match e {
+            ImageError::IoError(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        })?;
+        Self::new(&image.to_luma8())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} is black", path)))
+    }
+
+    pub fn new(image: &GrayImage) -> Option<Self> {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let mut rows = Vec::with_capacity(height);
+        let mut columns = Vec::with_capacity(width * height);
+        let mut total = 0.0;
+        for y in 0..height {
+            let start = columns.len();
+            let mut sum = 0.0;
+            for x in 0..width {
+                sum += image.get_pixel(x as u32, y as u32).0[0] as f64;
+                columns.push(sum);
+            }
+            for c in &mut columns[start..] {
+                *c = if sum > 0.0 { *c / sum } else { 1.0 };
+            }
+            total += sum;
+            rows.push(total);
+        }
+        if total <= 0.0 {
+            return None;
+        }
+        for r in &mut rows {
+            *r /= total;
+        }
+
+        Some(Self {
+            width,
+            height,
+            rows,
+            columns,
+        })
+    }
+
+    fn sample(&self, u: f64, v: f64) -> (f64, f64) {
+        let (y, fy) = pick(&self.rows, u);
+        let (x, fx) = pick(&self.columns[y * self.width..(y + 1) * self.width], v);
+        let size = self.width.max(self.height) as f64;
+        (
+            (2.0 * (x as f64 + fx) - self.width as f64) / size,
+            (2.0 * (y as f64 + fy) - self.height as f64) / size,
+        )
+    }
+}
+
+// the bin of `cdf` that `u` falls into, and where within it
+fn pick(cdf: &[f64], u: f64) -> (usize, f64) {
+    let i = cdf.iter().position(|&c| u < c).unwrap_or(cdf.len() - 1);
+    let low = if i == 0 { 0.0 } else { cdf[i - 1] };
+    let width = cdf[i] - low;
+    let f = if width > 0.0 { (u - low) / width } else { 0.5 };
+    (i, f.min(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    #[test]
+    fn test_polygon_fills_its_blades() {
+        let mut area = [0; 4];
+        for i in 0..32 {
+            for j in 0..32 {
+                let (u, v) = ((i as f64 + 0.5) / 32.0, (j as f64 + 0.5) / 32.0);
+                // a square with its corners on the axes has its edges at
+                // |x| + |y| = 1
+                let (a, b) = polygon(4, 0.0, u, v);
+                assert!(a.abs() + b.abs() <= 1.0 + 1e-12);
+                area[(a > 0.0) as usize * 2 + (b > 0.0) as usize] += 1;
+            }
+        }
+        assert_eq!(area, [256; 4]);
+    }
+
+    #[test]
+    fn test_bokeh_image_follows_brightness() {
+        // only the right half of the bottom row is lit
+        let image = GrayImage::from_fn(4, 2, |x, y| Luma([if y == 1 && x >= 2 { 255 } else { 0 }]));
+        let bokeh = BokehImage::new(&image).unwrap();
+        for &(u, v) in &[(0.0, 0.0), (0.3, 0.9), (0.99, 0.5)] {
+            let (a, b) = bokeh.sample(u, v);
+            assert!((0.0..=1.0).contains(&a), "{}", a);
+            assert!((0.0..=0.5).contains(&b), "{}", b);
+        }
+        assert!(BokehImage::new(&GrayImage::new(3, 3)).is_none());
+    }
+}
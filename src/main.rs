@@ -1,43 +1,93 @@
 mod aabb;
 mod aarect;
+mod animation;
+mod aov;
 mod r#box;
 mod bvh;
+mod bvh4;
 mod camera;
+mod checkpoint;
+mod denoise;
+mod film;
+mod filter;
+mod firefly;
+mod hdr;
 mod hittable;
+mod instance;
+mod lens;
 mod material;
+mod overlay;
+mod physical;
 mod ray;
+mod render;
+mod sampler;
 mod scene;
+mod scheduler;
 mod sphere;
+mod stats;
+mod stereo;
 mod texture;
+mod tonemap;
+mod transform;
 mod utils;
 #[allow(clippy::float_cmp)]
 mod vec3;
 pub use aabb::AABB;
 pub use aarect::{XyRect, XzRect, YzRect};
-pub use bvh::BvhNode;
-pub use camera::Camera;
-pub use hittable::{HitRecord, Hittable, HittableList, RotateY};
-use image::{ImageBuffer, Rgb, RgbImage};
-use indicatif::ProgressBar;
+pub use animation::{CameraPath, Interpolation, Keyframe, Sequence};
+pub use aov::AovSettings;
+pub use bvh::{BvhNode, LinearBvh};
+pub use bvh4::{Bvh4, SimdLevel};
+pub use camera::{
+    Camera, CameraModel, CubeMap, Equirectangular, Fisheye, Orthographic, Projection,
+};
+pub use checkpoint::{Checkpoint, FnvHasher};
+pub use denoise::Denoiser;
+pub use film::Film;
+pub use filter::Filter;
+pub use firefly::{Clamping, OutlierRejection};
+pub use hdr::{FloatImage, HdrFormat};
+pub use hittable::{HitRecord, Hittable, HittableList, RotateY, Tagged};
+use image::{Rgb, Rgba};
+pub use instance::Instance;
+pub use lens::{Aperture, BokehImage, Lens};
 pub use material::{Dielectric, DiffuseLight, Lambertian, Material, Metal};
+pub use overlay::{Corner, Overlay, OverlayInfo};
+pub use physical::{Focus, PhysicalCamera};
 pub use r#box::Box;
 pub use ray::Ray;
+pub use render::{AdaptiveSampling, RenderSettings};
+pub use sampler::{Sampler, SamplerKind};
 pub use scene::*;
+pub use scheduler::TileOrder;
 pub use sphere::Sphere;
-use std::sync::{mpsc::channel, Arc};
+pub use stats::Report;
+use std::env;
+use std::hash::Hasher;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+pub use stereo::{Stereo, StereoLayout, StereoSettings};
 pub use texture::{CheckerTexture, SolidColor, Texture};
-use threadpool::ThreadPool;
+pub use tonemap::{ToneMap, ToneMapping, Transfer};
+pub use transform::Transform;
 pub use utils::*;
 pub use vec3::{Color, Point3, Vec3};
 
 const AUTHOR: &str = "Yuanxin Cao";
+const CHECKPOINT_PATH: &str = "output/checkpoint.bin";
+const DEFAULT_SEED: u64 = 2020;
+const FONT_PATH: &str = "EncodeSans-Regular.ttf";
+
+// GITHUB_SHA is the associated commit ID
+// only available on GitHub Action
+fn commit() -> Option<String> {
+    option_env!("GITHUB_SHA").map(|x| x[0..6].to_owned())
+}
 
 fn get_text() -> String {
-    // GITHUB_SHA is the associated commit ID
-    // only available on GitHub Action
-    let github_sha = option_env!("GITHUB_SHA")
-        .map(|x| "@".to_owned() + &x[0..6])
-        .unwrap_or_default();
+    let github_sha = commit().map(|x| "@".to_owned() + &x).unwrap_or_default();
     format!("{}{}", AUTHOR, github_sha)
 }
 
@@ -45,45 +95,268 @@ fn is_ci() -> bool {
     option_env!("CI").unwrap_or_default() == "true"
 }
 
-fn ray_color(r: Ray, background: Color, world: &impl Hittable, depth: u32) -> Color {
-    let mut rec = HitRecord::new(Arc::new(Lambertian::new(Arc::new(SolidColor::new(
-        Color::zero(),
-    )))));
-
-    if depth == 0 {
-        return Color::zero();
+fn scene_hash(choice: i32, seed: u64, points: &[Point3], params: &[f64]) -> u64 {
+    let mut hasher = FnvHasher::new();
+    hasher.write_i32(choice);
+    hasher.write_u64(seed);
+    for p in points {
+        hasher.write_f64(p.x);
+        hasher.write_f64(p.y);
+        hasher.write_f64(p.z);
     }
-
-    if !world.hit(r, 0.001, INF, &mut rec) {
-        return background;
+    for &x in params {
+        hasher.write_f64(x);
     }
+    hasher.finish()
+}
 
-    let mut scattered = Ray::new(Point3::zero(), Vec3::zero());
-    let mut attenuation = Color::zero();
-    let emitted: Color = rec.mat_ptr.emitted(rec.u, rec.v, rec.p);
+// What main sets up once and every frame is rendered with
+struct Shot {
+    world: Arc<dyn Hittable>,
+    choice: i32,
+    vup: Vec3,
+    aspect_ratio: f64,
+    aperture: f64,
+    projection: Projection,
+    stereo: Option<StereoSettings>,
+    lens: Lens,
+    focus: Focus,
+    settings: RenderSettings,
+    aovs: Option<AovSettings>,
+    overlay: Option<Overlay>,
+    denoiser: Option<Denoiser>,
+    keep_noisy: bool,
+}
 
-    if !rec
-        .mat_ptr
-        .scatter(r, &rec, &mut attenuation, &mut scattered)
-    {
-        return emitted;
+// Renders the view to output/<filename>. The PNG at that path is written
+// last, so that a sequence can take it as the sign of a finished frame.
+fn render_frame(
+    shot: &Shot,
+    filename: &str,
+    view: Keyframe,
+    snapshots: bool,
+    checkpoint: Option<Checkpoint>,
+    report: &mut Report,
+) {
+    let Keyframe {
+        lookfrom,
+        lookat,
+        vfov,
+        ..
+    } = view;
+    let (vup, aspect_ratio, aperture) = (shot.vup, shot.aspect_ratio, shot.aperture);
+    let (projection, stereo, lens) = (shot.projection, shot.stereo, &shot.lens);
+    let dist_to_focus = physical::focus_distance(shot.focus, lookfrom, lookat, shot.world.as_ref());
+
+    let perspective = Camera::new(
+        lookfrom,
+        lookat,
+        vup,
+        vfov,
+        aspect_ratio,
+        aperture,
+        dist_to_focus,
+    )
+    .with_lens(lens.clone());
+    let cam: Arc<dyn CameraModel> = match (projection, &stereo) {
+        (Projection::Perspective, Some(stereo)) => {
+            Arc::new(Stereo::perspective(&perspective, stereo))
+        }
+        (Projection::Equirectangular, Some(stereo)) => {
+            Arc::new(Stereo::ods(lookfrom, lookat, vup, stereo))
+        }
+        (_, Some(_)) => {
+            eprintln!("stereo needs the perspective or the equirectangular projection");
+            process::exit(1);
+        }
+        (Projection::Perspective, None) => Arc::new(perspective),
+        (Projection::Orthographic { height }, None) => Arc::new(Orthographic::new(
+            lookfrom,
+            lookat,
+            vup,
+            height,
+            aspect_ratio,
+        )),
+        (Projection::Fisheye { fov }, None) => {
+            Arc::new(Fisheye::new(lookfrom, lookat, vup, fov, aspect_ratio))
+        }
+        (Projection::Equirectangular, None) => {
+            Arc::new(Equirectangular::new(lookfrom, lookat, vup))
+        }
+        (Projection::CubeMap, None) => Arc::new(CubeMap::new(lookfrom, lookat, vup)),
+    };
+
+    let (projection_kind, projection_param) = match projection {
+        Projection::Perspective => (0.0, 0.0),
+        Projection::Orthographic { height } => (1.0, height),
+        Projection::Fisheye { fov } => (2.0, fov),
+        Projection::Equirectangular => (3.0, 0.0),
+        Projection::CubeMap => (4.0, 0.0),
+    };
+    let (aperture_kind, blades, rotation) = match lens.aperture {
+        Aperture::Circle => (0.0, 0.0, 0.0),
+        Aperture::Polygon { blades, rotation } => (1.0, blades as f64, rotation),
+        Aperture::Image(_) => (2.0, 0.0, 0.0),
+    };
+    let savepath = format!("output/{}", filename);
+    let settings = RenderSettings {
+        snapshot_path: if snapshots {
+            Some(savepath.clone())
+        } else {
+            None
+        },
+        scene_hash: scene_hash(
+            shot.choice,
+            shot.settings.seed,
+            &[lookfrom, lookat],
+            &[
+                vfov,
+                aperture,
+                aspect_ratio,
+                dist_to_focus,
+                projection_kind,
+                projection_param,
+                stereo.map_or(0.0, |stereo| stereo.ipd),
+                stereo.and_then(|stereo| stereo.convergence).unwrap_or(INF),
+                stereo.map_or(0.0, |stereo| stereo.layout as u8 as f64 + 1.0),
+                aperture_kind,
+                blades,
+                rotation,
+                lens.anamorphic,
+                lens.cats_eye,
+                lens.tilt.0,
+                lens.tilt.1,
+                lens.shift.0,
+                lens.shift.1,
+            ],
+        ),
+        ..shot.settings.clone()
+    };
+
+    if let Some(checkpoint) = &checkpoint {
+        if checkpoint.scene_hash != settings.scene_hash
+            || checkpoint.settings_hash != settings.settings_hash()
+        {
+            eprintln!(
+                "{} was written for a different scene or settings, refusing to resume",
+                CHECKPOINT_PATH
+            );
+            process::exit(1);
+        }
+        println!(
+            "Resuming after {:.1} samples per pixel",
+            checkpoint.film.mean_samples()
+        );
     }
 
-    emitted + attenuation.elemul(ray_color(scattered, background, world, depth - 1))
+    let render_start = Instant::now();
+    let film = render::render(shot.world.clone(), cam, &settings, checkpoint);
+    let render_time = render_start.elapsed();
+    report.add_phase("render", render_time);
+    println!(
+        "Rendered {:.1} samples per pixel, estimated relative error {:.4}",
+        film.mean_samples(),
+        film.mean_relative_error()
+    );
+
+    // render commit ID and author name on image
+    let output_start = Instant::now();
+    let msg = get_text();
+    println!("Extra Info: {}", msg);
+    let info = OverlayInfo {
+        author: AUTHOR.to_owned(),
+        commit: commit(),
+        render_time,
+        samples_per_pixel: film.mean_samples(),
+        width: settings.width,
+        height: settings.height,
+    };
+    let caption = shot
+        .overlay
+        .as_ref()
+        .and_then(|overlay| match overlay.load_font() {
+            Ok(font) => Some((overlay, font, overlay.text(&info))),
+            Err(e) => {
+                eprintln!(
+                    "warning: skipping the caption, cannot load {}: {}",
+                    overlay.font_path, e
+                );
+                None
+            }
+        });
+
+    // the image at savepath comes last
+    let denoised: Option<Film> = shot
+        .denoiser
+        .map(|denoiser| denoise::denoise(&film, &denoiser));
+    let outputs: Vec<(&Film, String)> = match &denoised {
+        Some(denoised) if shot.keep_noisy => vec![
+            (denoised, format!("output/denoised_{}", filename)),
+            (&film, savepath.clone()),
+        ],
+        Some(denoised) => vec![(denoised, savepath.clone())],
+        None => vec![(&film, savepath.clone())],
+    };
+
+    // linear copies that keep the full range of the lights, for grading
+    let hdr_formats: &[HdrFormat] = &[HdrFormat::ExrHalf];
+    for (film, path) in &outputs {
+        let hdr_image = film.to_float_image();
+        for &format in hdr_formats {
+            let path = path.replace(".png", &format!(".{}", format.extension()));
+            hdr_image.save(&path, format).unwrap();
+        }
+    }
+    if shot.aovs.is_some() {
+        // full floats, so that the IDs stay exact
+        film.to_aov_image()
+            .save(
+                &format!("output/aovs_{}", filename.replace(".png", ".exr")),
+                HdrFormat::ExrFloat,
+            )
+            .unwrap();
+    }
+    if settings.adaptive.is_some() {
+        film.to_heatmap()
+            .save(format!("output/samples_{}", filename))
+            .unwrap();
+    }
+    for (film, path) in &outputs {
+        let mut image = film.to_rgb_image(&settings.tone_mapping);
+        if let Some((overlay, font, text)) = &caption {
+            overlay.draw(&mut image, text, font);
+        }
+        film::save_png(&image, path).unwrap();
+    }
+    report.add_phase("output", output_start.elapsed());
 }
 
 fn main() {
     // get environment variable CI, which is true for GitHub Action
     let is_ci = is_ci();
 
-    // jobs: split image into how many parts
-    // workers: maximum allowed concurrent running threads
-    let (n_jobs, n_workers): (usize, usize) = if is_ci { (32, 16) } else { (16, 16) };
+    // workers: one render thread per logical CPU
+    let n_workers: usize = scheduler::default_workers();
 
-    println!(
-        "CI: {}, using {} jobs and {} workers",
-        is_ci, n_jobs, n_workers
-    );
+    println!("CI: {}, using {} workers", is_ci, n_workers);
+
+    // set RESUME=1 to continue the render saved in CHECKPOINT_PATH
+    let mut checkpoint: Option<Checkpoint> = if matches!(env::var("RESUME"), Ok(v) if v == "1") {
+        Some(Checkpoint::load(CHECKPOINT_PATH).expect("failed to read checkpoint"))
+    } else {
+        None
+    };
+
+    // the seed drives the random scenes as well as the render, so the same
+    // seed gives the same image and a resumed render rebuilds the same world;
+    // set SEED=<n> to pick another one
+    let seed: u64 = match (&checkpoint, env::var("SEED")) {
+        (Some(checkpoint), _) => checkpoint.seed,
+        (None, Ok(seed)) => seed.parse().expect("SEED must be an unsigned integer"),
+        (None, Err(_)) => DEFAULT_SEED,
+    };
+    seed_rng(seed);
+    println!("Seed: {}", seed);
 
     let mut filename: &str = "";
 
@@ -91,6 +364,47 @@ fn main() {
     let mut width: u32 = 800;
     let mut samples_per_pixel: u32 = 100;
     let max_depth: u32 = 50;
+    // optional stopping criteria, samples_per_pixel stays the upper limit
+    let time_budget: Option<Duration> = None;
+    let target_error: Option<f64> = None;
+    // spend the samples where the image is noisy, e.g. min 16, max 4 * spp, 0.02
+    let adaptive: Option<AdaptiveSampling> = None;
+    // normals, albedo, depth, IDs and light groups for compositing, written to
+    // a multi-layer EXR, e.g. Some(AovSettings { light_groups: 2 })
+    let aovs: Option<AovSettings> = None;
+    // clean up low sample counts with the albedo and normal buffers, e.g.
+    // Some(Denoiser::default()); with keep_noisy the denoised image is written
+    // next to the noisy one as denoised_<filename>, otherwise in its place
+    let denoiser: Option<Denoiser> = None;
+    let keep_noisy: bool = true;
+    // firefly suppression, e.g. clamp indirect light to 10 and reject pixels
+    // 5 standard deviations above their neighbours
+    let clamping = Clamping {
+        max_direct: None,
+        max_indirect: None,
+    };
+    let outlier_rejection: Option<OutlierRejection> = None;
+    // caption drawn into the final PNGs, skipped with a warning if the font
+    // can't be read
+    let overlay: Option<Overlay> = Some(Overlay {
+        template: "{author}@{commit}\n{spp} spp, {resolution}, {time}".to_owned(),
+        font_path: FONT_PATH.to_owned(),
+        corner: Corner::BottomRight,
+        size: 16.0,
+        color: Rgb([255, 255, 255]),
+        margin: 8,
+        backing: Some(Rgba([0, 0, 0, 128])),
+    });
+    // build a BVH over the top-level objects, set to false to test them one by one
+    let use_bvh: bool = true;
+
+    // phase timings and ray counts are printed at the end, set
+    // STATS_JSON=<path> to also write them out for comparing runs
+    let stats_path: Option<String> = env::var("STATS_JSON").ok();
+    let mut report = Report {
+        commit: commit(),
+        ..Report::default()
+    };
 
     let mut world = HittableList::new();
 
@@ -101,7 +415,43 @@ fn main() {
     let mut aperture: f64 = 0.0;
     let mut background = Color::zero();
     let mut vfov: f64 = 40.0;
+    // e.g. Projection::Fisheye { fov: 180.0 }, the panoramas see all around
+    // lookfrom and set their own aspect ratio
+    let projection = Projection::Perspective;
+    // a view for each eye, e.g. Some(StereoSettings { ipd: 0.5, convergence:
+    // Some(30.0), layout: StereoLayout::SideBySide }), with the equirectangular
+    // projection this renders an ODS panorama
+    let stereo: Option<StereoSettings> = None;
+    // a real camera in place of vfov and aperture, which also sets the
+    // exposure, e.g. Some(PhysicalCamera { sensor: (36.0, 24.0), focal_length:
+    // 85.0, f_number: 2.8, shutter: 1.0 / 125.0, iso: 100.0, units_per_meter:
+    // 10.0 })
+    let physical: Option<PhysicalCamera> = None;
+    // sharp at lookat, at the first surface in the middle of the view with
+    // Focus::CenterHit, or at Focus::Distance(d)
+    let focus = Focus::LookAt;
+    // render frames first..=last of a camera path instead of a single image,
+    // to output/<name>_0001.png and so on; finished frames are skipped, so an
+    // interrupted sequence picks up where it stopped when run again. E.g. a
+    // turntable of 4 seconds at 24 fps: Some(Sequence { path:
+    // CameraPath::turntable(lookfrom, lookat, vfov, 4.0), fps: 24.0, first: 0,
+    // last: 95 }), or CameraPath::new(keyframes, Interpolation::CatmullRom)
+    let sequence: Option<Sequence> = None;
+    // the glass of the perspective camera: highlights shaped by e.g.
+    // Aperture::Polygon { blades: 6, rotation: 15.0 } or a grayscale image
+    // with Aperture::Image(Arc::new(BokehImage::load("bokeh.png").unwrap())),
+    // anamorphic squeeze, cat's eye vignetting, a tilted plane in focus and a
+    // shifted frame, e.g. shift: (0.0, 0.2) to look up at a building without
+    // converging verticals
+    let lens = Lens {
+        aperture: Aperture::Circle,
+        anamorphic: 1.0,
+        cats_eye: 0.0,
+        tilt: (0.0, 0.0),
+        shift: (0.0, 0.0),
+    };
 
+    let scene_start = Instant::now();
     let choice: i32 = 4;
     match choice {
         1 => {
@@ -149,83 +499,124 @@ fn main() {
         _ => {}
     };
 
-    let vup: Vec3 = Vec3::new(0.0, 1.0, 0.0);
-    let dist_to_focus: f64 = 10.0;
-    let height: u32 = ((width as f64) / aspect_ratio) as u32;
+    // object IDs follow the order the scene adds its objects in
+    world.tag_objects();
+    report.add_phase("scene build", scene_start.elapsed());
 
-    let cam = Camera::new(
-        lookfrom,
-        lookat,
+    let bvh_start = Instant::now();
+    let world: Arc<dyn Hittable> = if use_bvh {
+        Arc::new(LinearBvh::new(world))
+    } else {
+        Arc::new(world)
+    };
+    report.add_phase("BVH build", bvh_start.elapsed());
+
+    let vup: Vec3 = Vec3::new(0.0, 1.0, 0.0);
+    // of each eye, the image holds both with stereo
+    let aspect_ratio = projection.aspect_ratio().unwrap_or(aspect_ratio);
+    if let Some(physical) = &physical {
+        vfov = physical.vfov(aspect_ratio);
+        aperture = physical.aperture();
+    }
+    let image_aspect_ratio = match &stereo {
+        Some(stereo) => stereo.layout.aspect_ratio(aspect_ratio),
+        None => aspect_ratio,
+    };
+    let height: u32 = ((width as f64) / image_aspect_ratio) as u32;
+
+    // progressive: passes of 10 samples, the image on disk is refreshed every
+    // 5 passes or once a minute, so a stopped render still leaves a result
+    let settings = RenderSettings {
+        width,
+        height,
+        samples_per_pixel,
+        max_depth,
+        background,
+        tile_size: 32,
+        tile_order: TileOrder::Spiral,
+        n_workers,
+        pass_samples: 10,
+        snapshot_passes: Some(5),
+        snapshot_interval: Some(Duration::from_secs(60)),
+        tone_mapping: ToneMapping {
+            exposure: physical.map_or(0.0, |physical| physical.exposure()),
+            operator: ToneMap::Clamp,
+            white_point: None,
+            transfer: Transfer::Srgb,
+            dither: true,
+        },
+        time_budget,
+        target_error,
+        adaptive,
+        sampler: SamplerKind::Sobol,
+        filter: Filter::Gaussian {
+            radius: 1.5,
+            sigma: 0.5,
+        },
+        // the denoiser is guided by the albedo and normal AOVs
+        aovs: aovs.or_else(|| denoiser.map(|_| AovSettings { light_groups: 1 })),
+        clamping,
+        outlier_rejection,
+        checkpoint_path: Some(CHECKPOINT_PATH.to_owned()),
+        seed,
+        // set for each frame by render_frame
+        snapshot_path: None,
+        scene_hash: 0,
+    };
+    let shot = Shot {
+        world,
+        choice,
         vup,
-        vfov,
         aspect_ratio,
         aperture,
-        dist_to_focus,
-    );
+        projection,
+        stereo,
+        lens,
+        focus,
+        settings,
+        aovs,
+        overlay,
+        denoiser,
+        keep_noisy,
+    };
 
-    // create a channel to send objects between threads
-
-    let (tx, rx) = channel();
-    let pool = ThreadPool::new(n_workers);
-    let bar = ProgressBar::new(n_jobs as u64);
-
-    for i in 0..n_jobs {
-        let tx = tx.clone();
-        let world_ = world.clone();
-        pool.execute(move || {
-            // here, we render some of the rows of image in one thread
-            let row_begin = height as usize * i / n_jobs;
-            let row_end = height as usize * (i + 1) / n_jobs;
-            let render_height = row_end - row_begin;
-            let mut img: RgbImage = ImageBuffer::new(width, render_height as u32);
-            for x in 0..width {
-                // img_y is the row in partial rendered image
-                // y is real position in final image
-                for (img_y, y) in (row_begin..row_end).enumerate() {
-                    let y = y as u32;
-                    let pixel = img.get_pixel_mut(x, img_y as u32);
-                    let mut pixel_color: Color = Color::zero();
-
-                    for _ in 0..samples_per_pixel {
-                        let u: f64 = x as f64 / (width - 1) as f64;
-                        let v: f64 = y as f64 / (height - 1) as f64;
-                        let rr = cam.get_ray(u, v);
-                        pixel_color += ray_color(rr, background, &world_, max_depth);
-                    }
-                    pixel_color = pixel_color / (samples_per_pixel as f64);
-
-                    *pixel = Rgb([
-                        (clamp(pixel_color.x.sqrt(), 0.0, 0.999) * 256.0) as u8,
-                        (clamp(pixel_color.y.sqrt(), 0.0, 0.999) * 256.0) as u8,
-                        (clamp(pixel_color.z.sqrt(), 0.0, 0.999) * 256.0) as u8,
-                    ]);
+    match &sequence {
+        None => {
+            let view = Keyframe {
+                time: 0.0,
+                lookfrom,
+                lookat,
+                vfov,
+            };
+            render_frame(&shot, filename, view, true, checkpoint, &mut report);
+        }
+        Some(sequence) => {
+            for frame in sequence.frames() {
+                let filename = animation::frame_filename(filename, frame);
+                let savepath = format!("output/{}", filename);
+                if Path::new(&savepath).exists() {
+                    println!("Skipping {}, it was rendered before", savepath);
+                    continue;
                 }
-            }
-            // send row range and rendered image to main thread
-            tx.send((row_begin..row_end, img))
-                .expect("failed to send result");
-        });
-    }
-
-    let mut result: RgbImage = ImageBuffer::new(width, height);
+                println!("Frame {}", frame);
 
-    for (rows, data) in rx.iter().take(n_jobs) {
-        // idx is the corrsponding row in partial-rendered image
-        for (idx, row) in rows.enumerate() {
-            for col in 0..width {
-                let row = row as u32;
-                let idx = idx as u32;
-                *result.get_pixel_mut(col, row) = *data.get_pixel(col, idx);
+                let mut view = sequence.camera(frame);
+                // a physical camera keeps its focal length
+                if physical.is_some() {
+                    view.vfov = vfov;
+                }
+                // only the first frame left to render can be the one in the
+                // checkpoint; frames only show up once they are done, which
+                // is how finished frames are told apart from interrupted ones
+                let checkpoint = checkpoint.take();
+                render_frame(&shot, &filename, view, false, checkpoint, &mut report);
             }
         }
-        bar.inc(1);
     }
-    bar.finish();
-
-    // render commit ID and author name on image
-    let msg = get_text();
-    println!("Extra Info: {}", msg);
 
-    let savepath = format!("output/{}", filename);
-    result.save(savepath).unwrap();
+    report.counters = stats::totals();
+    report.print();
+    if let Some(path) = &stats_path {
+        report.save_json(path).unwrap();
+    }
 }
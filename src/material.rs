@@ -16,6 +16,14 @@ pub trait Material: Send + Sync {
     fn emitted(&self, _u: f64, _v: f64, _p: Point3) -> Color {
         Color::zero()
     }
+    // the surface color for the albedo AOV
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        Color::zero()
+    }
+    // which light group the emitted light is counted in
+    fn light_group(&self) -> usize {
+        0
+    }
 }
 
 // Lambertian
@@ -44,6 +52,10 @@ impl Material for Lambertian {
 
         true
     }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.albedo.value(rec.u, rec.v, rec.p)
+    }
 }
 
 // Metal
@@ -74,6 +86,10 @@ impl Material for Metal {
 
         scattered.direction * rec.normal > 0.0
     }
+
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        self.albedo
+    }
 }
 
 // Dielectric
@@ -131,17 +147,26 @@ impl Material for Dielectric {
 
         true
     }
+
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        Color::ones()
+    }
 }
 
 // Diffuse light
 
 pub struct DiffuseLight {
     pub emit: Arc<dyn Texture>,
+    pub group: usize,
 }
 
 impl DiffuseLight {
     pub fn new(emit: Arc<dyn Texture>) -> Self {
-        Self { emit }
+        Self { emit, group: 0 }
+    }
+
+    pub fn with_group(emit: Arc<dyn Texture>, group: usize) -> Self {
+        Self { emit, group }
     }
 }
 
@@ -159,4 +184,14 @@ impl Material for DiffuseLight {
     fn emitted(&self, u: f64, v: f64, p: Point3) -> Color {
         self.emit.value(u, v, p)
     }
+
+    // the color of the light, as bright as a white surface at most
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        let c = self.emit.value(rec.u, rec.v, rec.p);
+        Color::new(fmin(c.x, 1.0), fmin(c.y, 1.0), fmin(c.z, 1.0))
+    }
+
+    fn light_group(&self) -> usize {
+        self.group
+    }
 }
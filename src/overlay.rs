@@ -0,0 +1,195 @@
+use image::{Rgb, RgbImage, Rgba};
+use imageproc::drawing::draw_text_mut;
+use rusttype::{point, Font, Scale};
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+// Overlay
+//
+// Draws a caption into a corner of the final image, like the author and
+// commit it was rendered from. The caption is a template whose placeholders
+// are filled in from the render: {author}, {commit}, {time}, {spp} and
+// {resolution}. It may span several lines.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Clone, Debug)]
+pub struct Overlay {
+    pub template: String,
+    // a TrueType font, read when the overlay is drawn
+    pub font_path: String,
+    pub corner: Corner,
+    pub size: f32, // line height in pixels
+    pub color: Rgb<u8>,
+    // distance of the text from the image edges
+    pub margin: u32,
+    // a box behind the text, blended in by its alpha
+    pub backing: Option<Rgba<u8>>,
+}
+
+// what the placeholders stand for
+pub struct OverlayInfo {
+    pub author: String,
+    pub commit: Option<String>,
+    pub render_time: Duration,
+    pub samples_per_pixel: f64,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Overlay {
+    pub fn text(&self, info: &OverlayInfo) -> String {
+        self.template
+            .replace("{author}", &info.author)
+            .replace("{commit}", info.commit.as_deref().unwrap_or("local"))
+            .replace("{time}", &format_duration(info.render_time))
+            .replace("{spp}", &format!("{:.0}", info.samples_per_pixel))
+            .replace("{resolution}", &format!("{}x{}", info.width, info.height))
+    }
+
+    pub fn load_font(&self) -> io::Result<Font<'static>> {
+        let bytes = fs::read(&self.font_path)?;
+        Font::try_from_vec(bytes).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a TrueType font", self.font_path),
+            )
+        })
+    }
+
+    pub fn draw(&self, image: &mut RgbImage, text: &str, font: &Font) {
+        let scale = Scale::uniform(self.size);
+        let v_metrics = font.v_metrics(scale);
+        let line_height = (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap).ceil() as u32;
+        let lines: Vec<&str> = text.lines().collect();
+        let widths: Vec<u32> = lines.iter().map(|l| text_width(font, scale, l)).collect();
+        let text_size = (
+            widths.iter().cloned().max().unwrap_or(0),
+            line_height * lines.len() as u32,
+        );
+
+        let pad = (self.size / 4.0).ceil() as u32;
+        let (x, y) = origin(
+            self.corner,
+            image.dimensions(),
+            text_size,
+            self.margin + pad,
+        );
+        if let Some(backing) = self.backing {
+            blend_rect(
+                image,
+                (x.saturating_sub(pad), y.saturating_sub(pad)),
+                (text_size.0 + 2 * pad, text_size.1 + 2 * pad),
+                backing,
+            );
+        }
+
+        for (i, (line, width)) in lines.iter().zip(widths.iter()).enumerate() {
+            // lines hug the side of the image the corner is on
+            let line_x = match self.corner {
+                Corner::TopRight | Corner::BottomRight => x + text_size.0 - width,
+                Corner::TopLeft | Corner::BottomLeft => x,
+            };
+            let line_y = y + i as u32 * line_height;
+            draw_text_mut(image, self.color, line_x, line_y, scale, font, line);
+        }
+    }
+}
+
+fn text_width(font: &Font, scale: Scale, text: &str) -> u32 {
+    font.layout(text, scale, point(0.0, 0.0))
+        .filter_map(|g| g.pixel_bounding_box())
+        .map(|bb| bb.max.x.max(0) as u32)
+        .max()
+        .unwrap_or(0)
+}
+
+// top left corner of a block of `text` pixels placed `margin` pixels from the
+// edges in `corner` of an image of `image` pixels
+fn origin(corner: Corner, image: (u32, u32), text: (u32, u32), margin: u32) -> (u32, u32) {
+    let right = image.0.saturating_sub(text.0 + margin);
+    let bottom = image.1.saturating_sub(text.1 + margin);
+    match corner {
+        Corner::TopLeft => (margin, margin),
+        Corner::TopRight => (right, margin),
+        Corner::BottomLeft => (margin, bottom),
+        Corner::BottomRight => (right, bottom),
+    }
+}
+
+fn blend_rect(image: &mut RgbImage, at: (u32, u32), size: (u32, u32), color: Rgba<u8>) {
+    let alpha = color.0[3] as f64 / 255.0;
+    let x1 = (at.0 + size.0).min(image.width());
+    let y1 = (at.1 + size.1).min(image.height());
+    for y in at.1..y1 {
+        for x in at.0..x1 {
+            let p = image.get_pixel_mut(x, y);
+            for i in 0..3 {
+                p.0[i] = (p.0[i] as f64 * (1.0 - alpha) + color.0[i] as f64 * alpha).round() as u8;
+            }
+        }
+    }
+}
+
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{:.1}s", d.as_secs_f64()),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text() {
+        let overlay = Overlay {
+            template: "{author}@{commit}\n{spp} spp, {resolution}, {time}".to_owned(),
+            font_path: String::new(),
+            corner: Corner::BottomRight,
+            size: 16.0,
+            color: Rgb([255; 3]),
+            margin: 8,
+            backing: None,
+        };
+        let mut info = OverlayInfo {
+            author: "Yuanxin Cao".to_owned(),
+            commit: Some("abc123".to_owned()),
+            render_time: Duration::from_millis(83_500),
+            samples_per_pixel: 99.6,
+            width: 800,
+            height: 450,
+        };
+        assert_eq!(
+            overlay.text(&info),
+            "Yuanxin Cao@abc123\n100 spp, 800x450, 1m 23s"
+        );
+        info.commit = None;
+        assert!(overlay.text(&info).starts_with("Yuanxin Cao@local\n"));
+    }
+
+    #[test]
+    fn test_origin_and_durations() {
+        let image = (800, 450);
+        let text = (200, 40);
+        assert_eq!(origin(Corner::TopLeft, image, text, 10), (10, 10));
+        assert_eq!(origin(Corner::TopRight, image, text, 10), (590, 10));
+        assert_eq!(origin(Corner::BottomLeft, image, text, 10), (10, 400));
+        assert_eq!(origin(Corner::BottomRight, image, text, 10), (590, 400));
+        // text wider than the image starts at its edge
+        assert_eq!(origin(Corner::BottomRight, (100, 20), text, 10), (0, 0));
+
+        assert_eq!(format_duration(Duration::from_millis(4_300)), "4.3s");
+        assert_eq!(format_duration(Duration::from_secs(3_725)), "1h 02m 05s");
+    }
+}
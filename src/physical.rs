@@ -0,0 +1,149 @@
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::Lambertian;
+use crate::ray::Ray;
+use crate::texture::SolidColor;
+use crate::utils::INF;
+use crate::vec3::{Color, Point3};
+use std::sync::Arc;
+
+// Physical camera
+//
+// Describes the camera the way a photographer would and derives what the
+// thin-lens camera needs from it. The field of view follows from the sensor
+// and the focal length, the aperture from the focal length and the f-number.
+// The image is cropped from the middle of the sensor when their shapes
+// differ. Shutter speed and ISO only set the exposure, nothing in the scenes
+// moves.
+
+// Where the lens focuses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Focus {
+    // in world units from the camera
+    Distance(f64),
+    LookAt,
+    // the first surface along the center of the view, or lookat if there's
+    // nothing there
+    CenterHit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhysicalCamera {
+    // width and height in millimeters, 36 x 24 for full frame
+    pub sensor: (f64, f64),
+    // in millimeters
+    pub focal_length: f64,
+    pub f_number: f64,
+    // in seconds
+    pub shutter: f64,
+    pub iso: f64,
+    // how many world units make a meter, which sizes the aperture
+    pub units_per_meter: f64,
+}
+
+// The radiance of the scenes is taken to be calibrated for daylight: a white
+// surface of radiance 1 is exposed right by the sunny 16 rule, f/16 with a
+// shutter of 1 / ISO seconds, which is an EV100 of log2(16^2 * 100).
+const SUNNY_16_EV100: f64 = 14.643_856_189_774_725;
+
+impl PhysicalCamera {
+    // vertical field of view in degrees for an image of `aspect_ratio`
+    pub fn vfov(&self, aspect_ratio: f64) -> f64 {
+        let (width, height) = self.sensor;
+        let used_height = height.min(width / aspect_ratio);
+        2.0 * (used_height / (2.0 * self.focal_length))
+            .atan()
+            .to_degrees()
+    }
+
+    // diameter of the entrance pupil in world units
+    pub fn aperture(&self) -> f64 {
+        self.focal_length / self.f_number / 1000.0 * self.units_per_meter
+    }
+
+    // exposure value at ISO 100
+    pub fn ev100(&self) -> f64 {
+        (self.f_number * self.f_number / self.shutter * 100.0 / self.iso).log2()
+    }
+
+    // in stops, to add to the tone mapping
+    pub fn exposure(&self) -> f64 {
+        SUNNY_16_EV100 - self.ev100()
+    }
+}
+
+// distance from `lookfrom` to the plane in focus
+pub fn focus_distance(focus: Focus, lookfrom: Point3, lookat: Point3, world: &dyn Hittable) -> f64 {
+    let to_lookat = (lookat - lookfrom).length();
+    match focus {
+        Focus::Distance(d) => d,
+        Focus::LookAt => to_lookat,
+        Focus::CenterHit => {
+            let mut rec = HitRecord::new(Arc::new(Lambertian::new(Arc::new(SolidColor::new(
+                Color::zero(),
+            )))));
+            let r = Ray::new(lookfrom, (lookat - lookfrom).unit());
+            if world.hit(r, 0.001, INF, &mut rec) {
+                rec.t
+            } else {
+                to_lookat
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::HittableList;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn test_physical_camera() {
+        let camera = PhysicalCamera {
+            sensor: (36.0, 24.0),
+            focal_length: 50.0,
+            f_number: 16.0,
+            shutter: 1.0 / 100.0,
+            iso: 100.0,
+            units_per_meter: 1.0,
+        };
+        // a 3:2 image uses the whole sensor, a 16:9 one is cropped in height
+        let full = 2.0 * (12.0f64 / 50.0).atan().to_degrees();
+        assert!((camera.vfov(1.5) - full).abs() < 1e-9);
+        assert!(camera.vfov(16.0 / 9.0) < full);
+        assert!((camera.vfov(1.0) - full).abs() < 1e-9);
+        assert!((camera.aperture() - 0.003_125).abs() < 1e-12);
+        assert!(camera.exposure().abs() < 1e-9);
+
+        // a stop wider and a stop faster cancel out
+        let faster = PhysicalCamera {
+            f_number: 16.0 / 2f64.sqrt(),
+            shutter: 1.0 / 200.0,
+            ..camera
+        };
+        assert!(faster.exposure().abs() < 1e-9);
+        let brighter = PhysicalCamera {
+            iso: 400.0,
+            ..camera
+        };
+        assert!((brighter.exposure() - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_autofocus() {
+        let mut world = HittableList::new();
+        world.add(Arc::new(Sphere::new(
+            Point3::new(0.0, 0.0, -6.0),
+            1.0,
+            Arc::new(Lambertian::new(Arc::new(SolidColor::new(Color::ones())))),
+        )));
+        let lookat = Point3::new(0.0, 0.0, -10.0);
+        let focus = |f| focus_distance(f, Point3::zero(), lookat, &world);
+
+        assert!((focus(Focus::CenterHit) - 5.0).abs() < 1e-9);
+        assert!((focus(Focus::LookAt) - 10.0).abs() < 1e-9);
+        assert!((focus(Focus::Distance(3.0)) - 3.0).abs() < 1e-9);
+        let behind = focus_distance(Focus::CenterHit, Point3::zero(), -lookat, &world);
+        assert!((behind - 10.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,631 @@
+use crate::aov::{AovSample, AovSettings};
+use crate::camera::CameraModel;
+use crate::checkpoint::{Checkpoint, FnvHasher};
+use crate::film::{self, Film, TileSamples};
+use crate::filter::Filter;
+use crate::firefly::{self, Clamping, OutlierRejection};
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::Lambertian;
+use crate::ray::Ray;
+use crate::sampler::{self, Sampler, SamplerKind};
+use crate::scheduler::{self, Tile, TileOrder};
+use crate::stats;
+use crate::texture::SolidColor;
+use crate::tonemap::ToneMapping;
+use crate::utils::*;
+use crate::vec3::{Color, Point3, Vec3};
+use indicatif::ProgressBar;
+use std::fs;
+use std::hash::Hasher;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    pub background: Color,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    pub n_workers: usize,
+    // progressive rendering: samples are taken in passes of `pass_samples` per
+    // pixel, and the current estimate is written to `snapshot_path` every
+    // `snapshot_passes` passes or every `snapshot_interval`, whichever is first
+    pub pass_samples: u32,
+    pub snapshot_passes: Option<u32>,
+    pub snapshot_interval: Option<Duration>,
+    pub snapshot_path: Option<String>,
+    // how snapshots are converted to 8 bits
+    pub tone_mapping: ToneMapping,
+    // stopping criteria: `samples_per_pixel` is an upper limit, the render
+    // also ends after the pass that brings the average relative error of the
+    // pixels below `target_error`, or before a pass that would not finish
+    // within `time_budget` of this run
+    pub time_budget: Option<Duration>,
+    pub target_error: Option<f64>,
+    // with adaptive sampling `samples_per_pixel` becomes the average budget,
+    // which is spent on the pixels that have not converged yet
+    pub adaptive: Option<AdaptiveSampling>,
+    pub sampler: SamplerKind,
+    // how samples are weighted into the pixels around them
+    pub filter: Filter,
+    // first-hit buffers and light groups alongside the beauty, see aov.rs
+    pub aovs: Option<AovSettings>,
+    // firefly suppression: clamping applies to every sample, outlier
+    // rejection to the film in snapshots and the final result
+    pub clamping: Clamping,
+    pub outlier_rejection: Option<OutlierRejection>,
+    // written together with every snapshot so that the render can be resumed
+    pub checkpoint_path: Option<String>,
+    // Seed of the random numbers used while rendering. Each sample derives its
+    // numbers from the seed, its pixel and its index within the pixel, and
+    // each pixel's samples are accumulated pass by pass, so a given seed
+    // gives a bit-identical film whatever the number of workers, tile size or
+    // tile order. Only a time budget can make two renders differ.
+    pub seed: u64,
+    // identifies the world and camera, checked before resuming a checkpoint
+    pub scene_hash: u64,
+}
+
+// A pixel stops taking samples once it has at least `min_samples` and the
+// relative error of its estimate is below `threshold`. No pixel takes more
+// than `max_samples`.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveSampling {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f64,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 450,
+            samples_per_pixel: 100,
+            max_depth: 50,
+            background: Color::zero(),
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+            n_workers: scheduler::default_workers(),
+            pass_samples: 100,
+            snapshot_passes: None,
+            snapshot_interval: None,
+            snapshot_path: None,
+            tone_mapping: ToneMapping::default(),
+            time_budget: None,
+            target_error: None,
+            adaptive: None,
+            sampler: SamplerKind::Sobol,
+            filter: Filter::default(),
+            aovs: None,
+            clamping: Clamping::default(),
+            outlier_rejection: None,
+            checkpoint_path: None,
+            seed: 0,
+            scene_hash: 0,
+        }
+    }
+}
+
+impl RenderSettings {
+    // hash of everything that changes what the film converges to or how the
+    // samples are split into passes
+    pub fn settings_hash(&self) -> u64 {
+        let mut hasher = FnvHasher::new();
+        hasher.write_u32(self.width);
+        hasher.write_u32(self.height);
+        hasher.write_u32(self.samples_per_pixel);
+        hasher.write_u32(self.max_depth);
+        hasher.write_f64(self.background.x);
+        hasher.write_f64(self.background.y);
+        hasher.write_f64(self.background.z);
+        hasher.write_u32(self.pass_samples);
+        hasher.write_u8(self.sampler as u8);
+        let (kind, params) = match self.filter {
+            Filter::Box { radius } => (0, [radius, 0.0, 0.0]),
+            Filter::Tent { radius } => (1, [radius, 0.0, 0.0]),
+            Filter::Gaussian { radius, sigma } => (2, [radius, sigma, 0.0]),
+            Filter::Mitchell { radius, b, c } => (3, [radius, b, c]),
+            Filter::Lanczos { radius } => (4, [radius, 0.0, 0.0]),
+        };
+        hasher.write_u8(kind);
+        for &x in &params {
+            hasher.write_f64(x);
+        }
+        if let Some(adaptive) = &self.adaptive {
+            hasher.write_u32(adaptive.min_samples);
+            hasher.write_u32(adaptive.max_samples);
+            hasher.write_f64(adaptive.threshold);
+        }
+        if let Some(aovs) = &self.aovs {
+            hasher.write_u64(aovs.light_groups as u64);
+        }
+        for limit in &[self.clamping.max_direct, self.clamping.max_indirect] {
+            hasher.write_f64(limit.unwrap_or(INF));
+        }
+        hasher.finish()
+    }
+}
+
+// Follows the path of a camera ray for up to `depth` bounces. With `aov` it
+// also records the first hit and which light group every bit of the radiance
+// came from.
+pub fn ray_color(
+    r: Ray,
+    background: Color,
+    world: &dyn Hittable,
+    depth: u32,
+    clamping: &Clamping,
+    mut aov: Option<&mut AovSample>,
+) -> Color {
+    let mut rec = HitRecord::new(Arc::new(Lambertian::new(Arc::new(SolidColor::new(
+        Color::zero(),
+    )))));
+    let mut color = Color::zero();
+    let mut throughput = Color::ones();
+    let mut r = r;
+    let mut rays = 0;
+
+    for bounce in 0..depth {
+        rays += 1;
+        if !world.hit(r, 0.001, INF, &mut rec) {
+            let radiance = clamping.apply(throughput.elemul(background), bounce);
+            if let Some(aov) = aov.as_deref_mut() {
+                aov.add_background(radiance);
+            }
+            color += radiance;
+            break;
+        }
+
+        let emitted = clamping.apply(
+            throughput.elemul(rec.mat_ptr.emitted(rec.u, rec.v, rec.p)),
+            bounce,
+        );
+        if let Some(aov) = aov.as_deref_mut() {
+            if bounce == 0 {
+                aov.hit = true;
+                aov.normal = rec.normal;
+                aov.albedo = rec.mat_ptr.albedo(&rec);
+                // camera rays aren't normalized, so t isn't a distance yet
+                aov.depth = rec.t * r.direction.length();
+                aov.position = rec.p;
+                aov.object_id = rec.object_id;
+                aov.material_id = rec.material_id;
+            }
+            aov.add_light(rec.mat_ptr.light_group(), emitted);
+        }
+        color += emitted;
+
+        let mut scattered = Ray::new(Point3::zero(), Vec3::zero());
+        let mut attenuation = Color::zero();
+        if !rec
+            .mat_ptr
+            .scatter(r, &rec, &mut attenuation, &mut scattered)
+        {
+            break;
+        }
+        throughput = throughput.elemul(attenuation);
+        r = scattered;
+    }
+
+    stats::record(|c| c.rays += rays);
+    color
+}
+
+fn render_tile(
+    tile: &Tile,
+    world: &dyn Hittable,
+    cam: &dyn CameraModel,
+    settings: &RenderSettings,
+    sampler: &Arc<dyn Sampler>,
+    plan: &[Range<u32>],
+) -> TileSamples {
+    sampler::install(Some(sampler.clone()));
+    let (width, height) = (settings.width, settings.height);
+    let mut result = TileSamples::new(
+        tile,
+        &settings.filter,
+        width,
+        height,
+        settings.aovs.as_ref(),
+    );
+    let mut aov: Option<AovSample> = settings.aovs.as_ref().map(AovSample::new);
+    let mut camera_rays = 0;
+
+    for (i, y) in (tile.y0..tile.y1).enumerate() {
+        for (j, x) in (tile.x0..tile.x1).enumerate() {
+            let k = i * tile.width() as usize + j;
+
+            // every sample of a pixel draws its random numbers from the
+            // sampler, seeded by the pixel and the index of the sample; the
+            // first two place the sample within the pixel
+            let pixel = (y * width + x) as usize;
+            let seed = derive_seed(settings.seed, pixel as u64);
+            for index in plan[pixel].clone() {
+                sampler::start_sample(x, y, seed, index);
+                let (jx, jy) = random_2d();
+                let (px, py) = (x as f64 + jx, y as f64 + jy);
+                if let Some(aov) = &mut aov {
+                    aov.reset();
+                }
+                let sample = match cam.get_ray(px / width as f64, py / height as f64) {
+                    Some(rr) => {
+                        camera_rays += 1;
+                        ray_color(
+                            rr,
+                            settings.background,
+                            world,
+                            settings.max_depth,
+                            &settings.clamping,
+                            aov.as_mut(),
+                        )
+                    }
+                    // outside of what the camera sees
+                    None => Color::zero(),
+                };
+                result.stats[k].add(film::luminance(sample));
+                if let (Some(aov), Some(aovs)) = (&aov, &mut result.aovs) {
+                    let d2 = (jx - 0.5) * (jx - 0.5) + (jy - 0.5) * (jy - 0.5);
+                    aovs.pixels[k].add(aov, d2);
+                }
+                let light_groups: &[Color] = aov.as_ref().map_or(&[], |aov| &aov.light_groups);
+                result.splat(&settings.filter, px, py, sample, light_groups);
+            }
+        }
+    }
+
+    sampler::install(None);
+    stats::record(|c| c.camera_rays += camera_rays);
+    stats::flush();
+    result
+}
+
+// Indices of the samples every pixel takes in the next pass. Without adaptive
+// sampling each pixel simply works its way up to `samples_per_pixel`; with it,
+// converged pixels drop out and the rest may go up to `max_samples`.
+fn plan_pass(film: &Film, settings: &RenderSettings) -> Vec<Range<u32>> {
+    let pass_samples = settings.pass_samples.max(1);
+    let (width, height) = (film.width as i64, film.height as i64);
+    let converged: Vec<bool> = match &settings.adaptive {
+        Some(adaptive) => (0..film.height)
+            .flat_map(|y| (0..film.width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let stats = film.stats(x, y);
+                stats.n >= adaptive.min_samples && stats.relative_error() <= adaptive.threshold
+            })
+            .collect(),
+        None => vec![false; (film.width * film.height) as usize],
+    };
+
+    let mut plan = Vec::with_capacity(converged.len());
+    for y in 0..height {
+        for x in 0..width {
+            // A pixel only stops once its neighbours have converged too. A few
+            // unlucky samples that all missed the light look perfectly
+            // converged on their own, but rarely across a whole neighbourhood.
+            let mut done = true;
+            for ny in (y - 1).max(0)..(y + 2).min(height) {
+                for nx in (x - 1).max(0)..(x + 2).min(width) {
+                    done &= converged[(ny * width + nx) as usize];
+                }
+            }
+
+            let limit = match &settings.adaptive {
+                Some(_) if done => 0,
+                Some(adaptive) => adaptive.max_samples,
+                None => settings.samples_per_pixel,
+            };
+            let n = film.samples(x as u32, y as u32);
+            plan.push(n..n + pass_samples.min(limit.saturating_sub(n)));
+        }
+    }
+
+    plan
+}
+
+// Renders until the film holds `settings.samples_per_pixel` samples per pixel
+// on average or a stopping criterion is met, continuing from `resume` if
+// given. The checkpoint must match the settings and scene.
+pub fn render(
+    world: Arc<dyn Hittable>,
+    cam: Arc<dyn CameraModel>,
+    settings: &RenderSettings,
+    resume: Option<Checkpoint>,
+) -> Film {
+    let tiles = scheduler::make_tiles(
+        settings.width,
+        settings.height,
+        settings.tile_size,
+        settings.tile_order,
+    );
+    let budget = settings.samples_per_pixel as u64 * (settings.width * settings.height) as u64;
+    let sampler = settings
+        .sampler
+        .build(settings.samples_per_pixel, settings.seed);
+
+    let (mut film, mut pass) = match resume {
+        Some(checkpoint) => (checkpoint.film, checkpoint.passes_done),
+        None => match &settings.aovs {
+            Some(aovs) => (Film::with_aovs(settings.width, settings.height, aovs), 0),
+            None => (Film::new(settings.width, settings.height), 0),
+        },
+    };
+    let mut samples_done = film.total_samples();
+    let bar = ProgressBar::new(budget);
+    bar.set_position(samples_done);
+
+    let start = Instant::now();
+    let mut last_pass: Option<Duration> = None;
+    let mut last_snapshot = Instant::now();
+    let mut passes_since_snapshot: u32 = 0;
+    let mut stopped_by: Option<&str> = None;
+    loop {
+        let plan: Vec<Range<u32>> = plan_pass(&film, settings);
+        if samples_done >= budget || plan.iter().all(|r| r.start == r.end) {
+            break;
+        }
+
+        // snapshots are taken here rather than right after a pass, so that
+        // none is written for the final pass
+        let due = matches!(settings.snapshot_passes, Some(k) if passes_since_snapshot >= k)
+            || matches!(settings.snapshot_interval, Some(t) if last_snapshot.elapsed() >= t);
+        if due && passes_since_snapshot > 0 {
+            if let Some(path) = &settings.snapshot_path {
+                let shown = postprocess(&film, settings);
+                let shown = shown.as_ref().unwrap_or(&film);
+                if let Err(e) = shown.save_snapshot(path, &settings.tone_mapping) {
+                    bar.println(format!("failed to write snapshot {}: {}", path, e));
+                }
+            }
+            if let Some(path) = &settings.checkpoint_path {
+                let checkpoint = Checkpoint {
+                    scene_hash: settings.scene_hash,
+                    settings_hash: settings.settings_hash(),
+                    seed: settings.seed,
+                    passes_done: pass,
+                    film,
+                };
+                if let Err(e) = checkpoint.save(path) {
+                    bar.println(format!("failed to write checkpoint {}: {}", path, e));
+                }
+                film = checkpoint.film;
+            }
+            last_snapshot = Instant::now();
+            passes_since_snapshot = 0;
+        }
+
+        if let (Some(budget), Some(last_pass)) = (settings.time_budget, last_pass) {
+            if start.elapsed() + last_pass > budget {
+                stopped_by = Some("time budget");
+                break;
+            }
+        }
+
+        pass += 1;
+        let pass_start = Instant::now();
+        let plan = Arc::new(plan);
+        let world = world.clone();
+        let cam = cam.clone();
+        let tile_settings = settings.clone();
+        let sampler = sampler.clone();
+        scheduler::run(
+            tiles.clone(),
+            settings.n_workers,
+            move |tile| {
+                render_tile(
+                    tile,
+                    world.as_ref(),
+                    cam.as_ref(),
+                    &tile_settings,
+                    &sampler,
+                    &plan,
+                )
+            },
+            |tile, result| {
+                let samples: u64 = result.stats.iter().map(|s| s.n as u64).sum();
+                film.add_tile(&tile, &result);
+                samples_done += samples;
+                bar.inc(samples);
+            },
+        );
+        passes_since_snapshot += 1;
+        last_pass = Some(pass_start.elapsed());
+
+        if let Some(target) = settings.target_error {
+            if film.mean_relative_error() <= target {
+                stopped_by = Some("target error");
+                break;
+            }
+        }
+    }
+    bar.finish();
+    if let Some(reason) = stopped_by {
+        println!(
+            "Stopped by the {} after {:.1} samples per pixel",
+            reason,
+            film.mean_samples()
+        );
+    }
+
+    // the render is complete, there is nothing left to resume
+    if let Some(path) = &settings.checkpoint_path {
+        let _ = fs::remove_file(path);
+    }
+
+    postprocess(&film, settings).unwrap_or(film)
+}
+
+// the film as it is shown with outliers rejected, None if it is shown as is
+fn postprocess(film: &Film, settings: &RenderSettings) -> Option<Film> {
+    settings
+        .outlier_rejection
+        .as_ref()
+        .map(|rejection| firefly::reject_outliers(film, rejection))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::LinearBvh;
+    use crate::camera::Camera;
+    use crate::film::Welford;
+    use crate::scene;
+
+    #[test]
+    fn test_plan_pass() {
+        let mut film = Film::new(4, 1);
+        let tile = Tile {
+            index: 0,
+            x0: 0,
+            y0: 0,
+            x1: 4,
+            y1: 1,
+        };
+        let stats = |m2| Welford {
+            n: 16,
+            mean: 0.5,
+            m2,
+        };
+        film.add_tile(
+            &tile,
+            &TileSamples {
+                bounds: tile,
+                sum: vec![Color::ones() * 8.0; 4],
+                weight: vec![16.0; 4],
+                abs_weight: vec![16.0; 4],
+                stats: vec![stats(0.0), stats(0.0), stats(0.0), stats(15.0)],
+                aovs: None,
+            },
+        );
+
+        let mut settings = RenderSettings {
+            width: 4,
+            height: 1,
+            samples_per_pixel: 20,
+            pass_samples: 8,
+            ..RenderSettings::default()
+        };
+        assert_eq!(
+            plan_pass(&film, &settings),
+            vec![16..20, 16..20, 16..20, 16..20]
+        );
+
+        settings.adaptive = Some(AdaptiveSampling {
+            min_samples: 16,
+            max_samples: 80,
+            threshold: 0.05,
+        });
+        // the third pixel converged but its neighbour has not
+        assert_eq!(
+            plan_pass(&film, &settings),
+            vec![16..16, 16..16, 16..24, 16..24]
+        );
+    }
+
+    #[test]
+    fn test_light_groups_add_up() {
+        let world: Arc<dyn Hittable> = Arc::new(scene::simple_light());
+        let cam: Arc<dyn CameraModel> = Arc::new(Camera::new(
+            Point3::new(26.0, -3.0, 6.0),
+            Point3::new(0.0, -2.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            20.0,
+            16.0 / 9.0,
+            0.0,
+            10.0,
+        ));
+        let settings = RenderSettings {
+            width: 32,
+            height: 18,
+            samples_per_pixel: 4,
+            max_depth: 10,
+            background: Color::new(0.1, 0.1, 0.2),
+            aovs: Some(AovSettings { light_groups: 2 }),
+            ..RenderSettings::default()
+        };
+        let film = render(world, cam, &settings, None);
+
+        let mut lit = [false; 3];
+        for y in 0..film.height {
+            for x in 0..film.width {
+                let groups: Vec<Color> = (0..3).map(|g| film.light_group(x, y, g)).collect();
+                for (g, c) in groups.iter().enumerate() {
+                    lit[g] |= c.length() > 0.0;
+                }
+                let sum = groups[0] + groups[1] + groups[2];
+                assert!((sum - film.pixel(x, y)).length() < 1e-5);
+            }
+        }
+        // both lights and the background contribute somewhere
+        assert_eq!(lit, [true; 3]);
+    }
+
+    #[test]
+    fn test_render_is_deterministic() {
+        let render_with = |n_workers, tile_size, tile_order, adaptive| {
+            // the scene is random too, and built from the same seed
+            seed_rng(42);
+            let mut world = scene::random_scene();
+            world.tag_objects();
+            let world: Arc<dyn Hittable> = Arc::new(LinearBvh::new(world));
+            let cam: Arc<dyn CameraModel> = Arc::new(Camera::new(
+                Point3::new(13.0, -2.0, 3.0),
+                Point3::zero(),
+                Vec3::new(0.0, 1.0, 0.0),
+                20.0,
+                16.0 / 9.0,
+                0.1,
+                10.0,
+            ));
+            let settings = RenderSettings {
+                width: 32,
+                height: 18,
+                samples_per_pixel: 8,
+                max_depth: 10,
+                background: Color::new(0.7, 0.8, 1.0),
+                tile_size,
+                tile_order,
+                n_workers,
+                pass_samples: 3,
+                adaptive,
+                filter: Filter::Mitchell {
+                    radius: 2.0,
+                    b: 1.0 / 3.0,
+                    c: 1.0 / 3.0,
+                },
+                aovs: Some(AovSettings { light_groups: 1 }),
+                seed: 42,
+                ..RenderSettings::default()
+            };
+            render(world, cam, &settings, None)
+        };
+        let adaptive = Some(AdaptiveSampling {
+            min_samples: 3,
+            max_samples: 16,
+            threshold: 0.1,
+        });
+
+        for &adaptive in &[None, adaptive] {
+            let expected = render_with(1, 32, TileOrder::Scanline, adaptive);
+            for &(n_workers, tile_size, tile_order) in &[
+                (3, 5, TileOrder::Spiral),
+                (4, 7, TileOrder::Hilbert),
+                (2, 1, TileOrder::Scanline),
+            ] {
+                let film = render_with(n_workers, tile_size, tile_order, adaptive);
+                for y in 0..film.height {
+                    for x in 0..film.width {
+                        assert_eq!(film.pixel(x, y), expected.pixel(x, y));
+                        assert_eq!(film.stats(x, y), expected.stats(x, y));
+                        assert_eq!(film.aov(x, y), expected.aov(x, y));
+                        assert_eq!(film.light_group(x, y, 1), expected.light_group(x, y, 1));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use crate::utils::*;
+use std::cell::RefCell;
+use std::sync::Arc;
+
+// Samplers
+//
+// A sampler decides the random numbers of every sample of a pixel. Sample
+// `index` of a pixel is a point in an unbounded number of dimensions, and the
+// dimensions are handed out in the order they are asked for: the lens takes
+// the first two, the first bounce the next few, and so on. Dimensions 2k and
+// 2k + 1 form a 2D point, which is where stratification pays off, so
+// `random_2d` skips a dimension if needed to start on an even one.
+//
+// While a tile is rendered its sampler is installed on the render thread and
+// `random_f64` reads from it, so cameras and materials draw their numbers as
+// before without knowing which sampler is in use.
+
+pub trait Sampler: Send + Sync {
+    // dimension `dim` of sample `index` of the pixel at (x, y), in [0, 1);
+    // `seed` is unique to the pixel
+    fn sample(&self, x: u32, y: u32, seed: u64, index: u32, dim: u32) -> f64;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent, // every dimension uniformly at random
+    Stratified,  // one jittered sample per stratum of every 2D projection
+    Sobol,       // padded 2D Sobol points with Owen scrambling
+    BlueNoise,   // a blue-noise mask over the image, rotated per sample
+}
+
+impl SamplerKind {
+    // `samples_per_pixel` is the number of samples the stratified sampler
+    // spreads over its strata, further samples start a new set; `seed` is the
+    // seed of the render
+    pub fn build(self, samples_per_pixel: u32, seed: u64) -> Arc<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Arc::new(IndependentSampler),
+            SamplerKind::Stratified => Arc::new(StratifiedSampler::new(samples_per_pixel)),
+            SamplerKind::Sobol => Arc::new(SobolSampler),
+            SamplerKind::BlueNoise => Arc::new(BlueNoiseSampler::new(seed)),
+        }
+    }
+}
+
+// uniform in [0, 1) from the top 53 bits of a hash
+fn to_unit(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn hash3(seed: u64, a: u64, b: u64) -> u64 {
+    derive_seed(derive_seed(seed, a), b)
+}
+
+pub struct IndependentSampler;
+
+impl Sampler for IndependentSampler {
+    fn sample(&self, _x: u32, _y: u32, seed: u64, index: u32, dim: u32) -> f64 {
+        to_unit(hash3(seed, index as u64, dim as u64))
+    }
+}
+
+pub struct StratifiedSampler {
+    samples: u32,
+    nx: u32,
+    ny: u32,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32) -> Self {
+        let samples = samples_per_pixel.max(1);
+        let mut nx = (samples as f64).sqrt() as u32;
+        while nx * nx < samples {
+            nx += 1;
+        }
+        let ny = (0..samples).step_by(nx as usize).count() as u32;
+        Self { samples, nx, ny }
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn sample(&self, _x: u32, _y: u32, seed: u64, index: u32, dim: u32) -> f64 {
+        // every pair of dimensions visits the nx * ny strata in its own order
+        let set = (index / self.samples) as u64;
+        let pair = hash3(seed, set, (dim / 2) as u64);
+        let stratum = permute(index % self.samples, self.nx * self.ny, pair as u32);
+        let jitter = to_unit(derive_seed(pair, (index * 2 + dim % 2) as u64));
+        match dim % 2 {
+            0 => ((stratum % self.nx) as f64 + jitter) / self.nx as f64,
+            _ => ((stratum / self.nx) as f64 + jitter) / self.ny as f64,
+        }
+    }
+}
+
+// element `i` of a pseudo-random permutation of 0..n selected by `p`
+// (Kensler, "Correlated Multi-Jittered Sampling")
+fn permute(i: u32, n: u32, p: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    let mut i = i;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+
+    i.wrapping_add(p) % n
+}
+
+// Every pair of dimensions is a 2D Sobol point set whose sample order is
+// shuffled and whose values are Owen-scrambled, both by hashing with a seed
+// of the pixel and pair (Burley, "Practical Hash-based Owen Scrambling").
+// Scrambling keeps the stratification of the points, so any power of two
+// samples of a pixel are well spread in every 2D projection.
+pub struct SobolSampler;
+
+impl Sampler for SobolSampler {
+    fn sample(&self, _x: u32, _y: u32, seed: u64, index: u32, dim: u32) -> f64 {
+        let pair = derive_seed(seed, (dim / 2) as u64);
+        let index = nested_uniform_scramble(index, pair as u32);
+        let x = sobol(index, dim % 2);
+        let x = nested_uniform_scramble(x, derive_seed(pair, (dim % 2) as u64) as u32);
+        x as f64 / (1u64 << 32) as f64
+    }
+}
+
+// the first two Sobol dimensions: van der Corput and x + 1
+fn sobol(index: u32, dim: u32) -> u32 {
+    let mut v: u32 = 1 << 31;
+    let mut x: u32 = 0;
+    let mut index = index;
+    while index != 0 {
+        if index & 1 != 0 {
+            x ^= v;
+        }
+        index >>= 1;
+        v = if dim == 0 { v >> 1 } else { v ^ (v >> 1) };
+    }
+
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// a hash in which every bit only depends on the bits below it, so that the
+// reversed version flips subtrees of the binary interval tree
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+    let mut x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+// Each dimension reads a 64 x 64 void-and-cluster mask, shifted by a
+// different amount per dimension and render seed, and the samples of a pixel
+// rotate that value along an R2 sequence (a Cranley-Patterson rotation).
+// Neighbouring pixels then get very different values, which pushes the error
+// into high frequencies where it looks much less like noise.
+const MASK_SIZE: usize = 64;
+
+pub struct BlueNoiseSampler {
+    mask: Vec<f64>,
+    // the shifts have to be the same for every pixel, so they can't come from
+    // the per-pixel seed
+    seed: u64,
+}
+
+impl BlueNoiseSampler {
+    pub fn new(seed: u64) -> Self {
+        let ranks = void_and_cluster(MASK_SIZE, 1.5, 0x5eed);
+        let n = ranks.len() as f64;
+        Self {
+            mask: ranks.iter().map(|&r| (r as f64 + 0.5) / n).collect(),
+            seed,
+        }
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn sample(&self, x: u32, y: u32, _seed: u64, index: u32, dim: u32) -> f64 {
+        // plastic-number steps, the R2 sequence spreads successive samples
+        // of a pixel evenly in 2D
+        const R2: [f64; 2] = [0.754_877_666_246_692_7, 0.569_840_290_998_053_2];
+        let h = hash3(self.seed, 0xb1_0e, dim as u64);
+        let sx = (x as usize + (h as usize & 0xffff)) % MASK_SIZE;
+        let sy = (y as usize + ((h >> 16) as usize & 0xffff)) % MASK_SIZE;
+        let v = self.mask[sy * MASK_SIZE + sx] + R2[(dim % 2) as usize] * index as f64;
+        v.fract()
+    }
+}
+
+// Ulichney's void-and-cluster method: ranks 0..size * size for a toroidal
+// grid such that every prefix of the ranking is an evenly spread point set.
+fn void_and_cluster(size: usize, sigma: f64, seed: u64) -> Vec<u32> {
+    let n = size * size;
+
+    // Gaussian energy of a point as seen from an offset, wrapping around
+    let mut kernel = vec![0.0; n];
+    for dy in 0..size {
+        for dx in 0..size {
+            let wx = dx.min(size - dx) as f64;
+            let wy = dy.min(size - dy) as f64;
+            kernel[dy * size + dx] = (-(wx * wx + wy * wy) / (2.0 * sigma * sigma)).exp();
+        }
+    }
+    let splat = |energy: &mut Vec<f64>, p: usize, sign: f64| {
+        let (px, py) = (p % size, p / size);
+        for y in 0..size {
+            let dy = (y + size - py) % size;
+            for x in 0..size {
+                let dx = (x + size - px) % size;
+                energy[y * size + x] += sign * kernel[dy * size + dx];
+            }
+        }
+    };
+    // tightest cluster (most energy among the points) or largest void (least
+    // energy among the gaps)
+    let extreme = |energy: &[f64], points: &[bool], cluster: bool| -> usize {
+        let mut best = None;
+        for p in 0..n {
+            if points[p] != cluster {
+                continue;
+            }
+            let better = match best {
+                None => true,
+                Some(b) if cluster => energy[p] > energy[b],
+                Some(b) => energy[p] < energy[b],
+            };
+            if better {
+                best = Some(p);
+            }
+        }
+        best.unwrap()
+    };
+
+    // initial pattern: a tenth of the cells, spread out by repeatedly moving
+    // the tightest cluster into the largest void
+    let mut points = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let mut ones = 0;
+    let mut state = seed;
+    while ones < n / 10 {
+        state = mix64(state.wrapping_add(0x9e37_79b9_7f4a_7c15));
+        let p = (state % n as u64) as usize;
+        if !points[p] {
+            points[p] = true;
+            splat(&mut energy, p, 1.0);
+            ones += 1;
+        }
+    }
+    loop {
+        let cluster = extreme(&energy, &points, true);
+        points[cluster] = false;
+        splat(&mut energy, cluster, -1.0);
+        let void = extreme(&energy, &points, false);
+        points[void] = true;
+        splat(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; n];
+
+    // the initial points are ranked by removing the tightest cluster first
+    let mut remaining = points.clone();
+    let mut remaining_energy = energy.clone();
+    for rank in (0..ones).rev() {
+        let cluster = extreme(&remaining_energy, &remaining, true);
+        remaining[cluster] = false;
+        splat(&mut remaining_energy, cluster, -1.0);
+        ranks[cluster] = rank as u32;
+    }
+
+    // and the rest by filling the largest void
+    for rank in ones..n {
+        let void = extreme(&energy, &points, false);
+        points[void] = true;
+        splat(&mut energy, void, 1.0);
+        ranks[void] = rank as u32;
+    }
+
+    ranks
+}
+
+// The sample stream of the current thread: while a sampler is installed,
+// `random_f64` returns its dimensions one after another.
+struct SampleStream {
+    sampler: Arc<dyn Sampler>,
+    x: u32,
+    y: u32,
+    seed: u64,
+    index: u32,
+    dim: u32,
+}
+
+thread_local! {
+    static STREAM: RefCell<Option<SampleStream>> = RefCell::new(None);
+}
+
+// installs `sampler` on this thread, or goes back to plain random numbers
+pub fn install(sampler: Option<Arc<dyn Sampler>>) {
+    STREAM.with(|stream| {
+        *stream.borrow_mut() = sampler.map(|sampler| SampleStream {
+            sampler,
+            x: 0,
+            y: 0,
+            seed: 0,
+            index: 0,
+            dim: 0,
+        });
+    });
+}
+
+// starts sample `index` of the pixel at (x, y) from its first dimension
+pub fn start_sample(x: u32, y: u32, seed: u64, index: u32) {
+    STREAM.with(|stream| {
+        if let Some(s) = stream.borrow_mut().as_mut() {
+            s.x = x;
+            s.y = y;
+            s.seed = seed;
+            s.index = index;
+            s.dim = 0;
+        }
+    });
+}
+
+pub(crate) fn next_1d() -> Option<f64> {
+    STREAM.with(|stream| {
+        stream.borrow_mut().as_mut().map(|s| {
+            let v = s.sampler.sample(s.x, s.y, s.seed, s.index, s.dim);
+            s.dim += 1;
+            v
+        })
+    })
+}
+
+pub(crate) fn align_2d() {
+    STREAM.with(|stream| {
+        if let Some(s) = stream.borrow_mut().as_mut() {
+            s.dim += s.dim % 2;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // whether the points fall one each into the n x n grid cells
+    fn is_stratified(points: &[(f64, f64)], n: usize) -> bool {
+        let mut cells = vec![0; n * n];
+        for &(x, y) in points {
+            cells[(y * n as f64) as usize * n + (x * n as f64) as usize] += 1;
+        }
+        cells.iter().all(|&c| c == 1)
+    }
+
+    fn points(sampler: &dyn Sampler, n: u32, dim: u32) -> Vec<(f64, f64)> {
+        (0..n)
+            .map(|i| {
+                let x = sampler.sample(3, 5, 0x1234, i, dim);
+                let y = sampler.sample(3, 5, 0x1234, i, dim + 1);
+                assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+                (x, y)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stratified_sampler() {
+        let sampler = StratifiedSampler::new(16);
+        for &dim in &[0, 2, 10] {
+            assert!(is_stratified(&points(&sampler, 16, dim), 4));
+        }
+    }
+
+    #[test]
+    fn test_sobol_sampler() {
+        // scrambled (0, 2)-sequence: 16 points fill the 4 x 4 grid, and also
+        // every 16 x 1 and 1 x 16 grid
+        for &dim in &[0, 2, 10] {
+            let points = points(&SobolSampler, 16, dim);
+            assert!(is_stratified(&points, 4));
+            let mut xs: Vec<usize> = points.iter().map(|p| (p.0 * 16.0) as usize).collect();
+            let mut ys: Vec<usize> = points.iter().map(|p| (p.1 * 16.0) as usize).collect();
+            xs.sort_unstable();
+            ys.sort_unstable();
+            assert_eq!(xs, (0..16).collect::<Vec<_>>());
+            assert_eq!(ys, (0..16).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_blue_noise_follows_render_seed() {
+        let row = |sampler: &BlueNoiseSampler| -> Vec<f64> {
+            (0..8)
+                .map(|x| sampler.sample(x, 0, x as u64, 0, 2))
+                .collect()
+        };
+        let a = BlueNoiseSampler::new(1);
+        assert_eq!(row(&a), row(&BlueNoiseSampler::new(1)));
+        assert_ne!(row(&a), row(&BlueNoiseSampler::new(2)));
+    }
+
+    #[test]
+    fn test_void_and_cluster_ranks() {
+        let mut ranks = void_and_cluster(16, 1.5, 1);
+        ranks.sort_unstable();
+        assert_eq!(ranks, (0..256).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_stream() {
+        let sampler: Arc<dyn Sampler> = Arc::new(SobolSampler);
+        install(Some(sampler.clone()));
+        start_sample(1, 2, 7, 3);
+        let a = random_f64();
+        let (b, c) = random_2d();
+        install(None);
+        assert_eq!(a, sampler.sample(1, 2, 7, 3, 0));
+        assert_eq!(b, sampler.sample(1, 2, 7, 3, 2));
+        assert_eq!(c, sampler.sample(1, 2, 7, 3, 3));
+    }
+
+    // error against a 4096 spp reference of random_scene at equal spp
+    #[test]
+    #[ignore]
+    fn bench_samplers() {
+        use crate::camera::{Camera, CameraModel};
+        use crate::render::{self, RenderSettings};
+        use crate::vec3::{Color, Point3, Vec3};
+        use crate::{scene, LinearBvh};
+
+        seed_rng(1);
+        let world: Arc<dyn crate::Hittable> = Arc::new(LinearBvh::new(scene::random_scene()));
+        let (width, height) = (96, 54);
+        let cam: Arc<dyn CameraModel> = Arc::new(Camera::new(
+            Point3::new(13.0, -2.0, 3.0),
+            Point3::zero(),
+            Vec3::new(0.0, 1.0, 0.0),
+            20.0,
+            16.0 / 9.0,
+            0.1,
+            10.0,
+        ));
+        let settings = |samples_per_pixel, sampler| RenderSettings {
+            width,
+            height,
+            samples_per_pixel,
+            background: Color::new(0.7, 0.8, 1.0),
+            pass_samples: samples_per_pixel,
+            sampler,
+            seed: 7,
+            ..RenderSettings::default()
+        };
+
+        let reference = render::render(
+            world.clone(),
+            cam.clone(),
+            &settings(4096, SamplerKind::Independent),
+            None,
+        );
+        for &spp in &[4, 16, 64] {
+            for &kind in &[
+                SamplerKind::Independent,
+                SamplerKind::Stratified,
+                SamplerKind::Sobol,
+                SamplerKind::BlueNoise,
+            ] {
+                let film = render::render(world.clone(), cam.clone(), &settings(spp, kind), None);
+                let mut squared_error = 0.0;
+                for y in 0..height {
+                    for x in 0..width {
+                        let d = film.pixel(x, y) - reference.pixel(x, y);
+                        squared_error += d.squared_length();
+                    }
+                }
+                let rmse = (squared_error / (width * height) as f64).sqrt();
+                println!("{} spp, {:?}: rmse {:.5}", spp, kind, rmse);
+            }
+        }
+    }
+}
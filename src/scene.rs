@@ -1,26 +1,42 @@
 use crate::aarect::{XyRect, XzRect};
-use crate::bvh::BvhNode;
-use crate::hittable::{HittableList, RotateY};
+use crate::bvh::LinearBvh;
+use crate::hittable::{Hittable, HittableList, RotateY, Tagged};
+use crate::instance::Instance;
 use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal};
 use crate::r#box::Box;
 use crate::sphere::Sphere;
 use crate::texture::{CheckerTexture, SolidColor};
+use crate::transform::Transform;
 use crate::utils::*;
-use crate::vec3::{Color, Point3};
+use crate::vec3::{Color, Point3, Vec3};
 use std::sync::Arc;
 
+// material IDs, the same kind of surface gets the same ID in every scene
+const GROUND: u32 = 1;
+const GLASS: u32 = 2;
+const DIFFUSE: u32 = 3;
+const METAL: u32 = 4;
+const TOWER: u32 = 5;
+const LIGHT: u32 = 6;
+
 pub fn random_scene() -> HittableList {
     let mut world = HittableList::new();
+    let mut add = |object: Arc<dyn Hittable>, material_id: u32| {
+        world.add(Arc::new(Tagged::material(object, material_id)));
+    };
 
     // Ground
-    world.add(Arc::new(Sphere::new(
-        Point3::new(0.0, 1000.0, 0.0),
-        1000.0,
-        Arc::new(Lambertian::new(Arc::new(CheckerTexture::new(
-            Color::new(0.2, 0.3, 0.1),
-            Color::new(0.9, 0.9, 0.9),
-        )))),
-    )));
+    add(
+        Arc::new(Sphere::new(
+            Point3::new(0.0, 1000.0, 0.0),
+            1000.0,
+            Arc::new(Lambertian::new(Arc::new(CheckerTexture::new(
+                Color::new(0.2, 0.3, 0.1),
+                Color::new(0.9, 0.9, 0.9),
+            )))),
+        )),
+        GROUND,
+    );
 
     for a in -11..11 {
         for b in -11..11 {
@@ -34,95 +50,121 @@ pub fn random_scene() -> HittableList {
             if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
                 if choose_mat < 0.8 {
                     let albedo: Color = Color::random().elemul(Color::random());
-                    world.add(Arc::new(Sphere::new(
-                        center,
-                        0.2,
-                        Arc::new(Lambertian::new(Arc::new(SolidColor::new(albedo)))),
-                    )));
+                    add(
+                        Arc::new(Sphere::new(
+                            center,
+                            0.2,
+                            Arc::new(Lambertian::new(Arc::new(SolidColor::new(albedo)))),
+                        )),
+                        DIFFUSE,
+                    );
                 } else if choose_mat < 0.95 {
                     let albedo: Color = Color::random_range(0.5, 1.0);
                     let fuzz: f64 = random_f64_range(0.0, 0.5);
-                    world.add(Arc::new(Sphere::new(
-                        center,
-                        0.2,
-                        Arc::new(Metal::new(albedo, fuzz)),
-                    )));
+                    add(
+                        Arc::new(Sphere::new(center, 0.2, Arc::new(Metal::new(albedo, fuzz)))),
+                        METAL,
+                    );
                 } else {
-                    world.add(Arc::new(Sphere::new(
-                        center,
-                        0.2,
-                        Arc::new(Dielectric::new(1.5)),
-                    )));
+                    add(
+                        Arc::new(Sphere::new(center, 0.2, Arc::new(Dielectric::new(1.5)))),
+                        GLASS,
+                    );
                 }
             }
         }
     }
 
-    world.add(Arc::new(Sphere::new(
-        Point3::new(0.0, -1.0, 0.0),
-        1.0,
-        Arc::new(Dielectric::new(1.5)),
-    )));
-    world.add(Arc::new(Sphere::new(
-        Point3::new(-4.0, -1.0, 0.0),
-        1.0,
-        Arc::new(Lambertian::new(Arc::new(SolidColor::new(Color::new(
-            0.4, 0.2, 0.1,
-        ))))),
-    )));
-    world.add(Arc::new(Sphere::new(
-        Point3::new(4.0, -1.0, 0.0),
-        1.0,
-        Arc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0)),
-    )));
+    add(
+        Arc::new(Sphere::new(
+            Point3::new(0.0, -1.0, 0.0),
+            1.0,
+            Arc::new(Dielectric::new(1.5)),
+        )),
+        GLASS,
+    );
+    add(
+        Arc::new(Sphere::new(
+            Point3::new(-4.0, -1.0, 0.0),
+            1.0,
+            Arc::new(Lambertian::new(Arc::new(SolidColor::new(Color::new(
+                0.4, 0.2, 0.1,
+            ))))),
+        )),
+        DIFFUSE,
+    );
+    add(
+        Arc::new(Sphere::new(
+            Point3::new(4.0, -1.0, 0.0),
+            1.0,
+            Arc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0)),
+        )),
+        METAL,
+    );
 
     world
 }
 
 pub fn simple_light() -> HittableList {
     let mut objects = HittableList::new();
+    let mut add = |object: Arc<dyn Hittable>, material_id: u32| {
+        objects.add(Arc::new(Tagged::material(object, material_id)));
+    };
 
     // Ground
     let checker: Color = Color::random();
-    objects.add(Arc::new(Sphere::new(
-        Point3::new(0.0, 1000.0, 0.0),
-        1000.0,
-        Arc::new(Lambertian::new(Arc::new(CheckerTexture::new(
-            checker,
-            Color::new(0.9, 0.9, 0.9),
-        )))),
-    )));
+    add(
+        Arc::new(Sphere::new(
+            Point3::new(0.0, 1000.0, 0.0),
+            1000.0,
+            Arc::new(Lambertian::new(Arc::new(CheckerTexture::new(
+                checker,
+                Color::new(0.9, 0.9, 0.9),
+            )))),
+        )),
+        GROUND,
+    );
 
     // Sphere
-    objects.add(Arc::new(Box::new(
-        Point3::new(1.0, -2.0, 0.0),
-        Point3::new(3.0, 0.0, 2.0),
-        Arc::new(Lambertian::new(Arc::new(SolidColor::new(Color::new(
-            0.48, 0.83, 0.53,
-        ))))),
-    )));
+    add(
+        Arc::new(Box::new(
+            Point3::new(1.0, -2.0, 0.0),
+            Point3::new(3.0, 0.0, 2.0),
+            Arc::new(Lambertian::new(Arc::new(SolidColor::new(Color::new(
+                0.48, 0.83, 0.53,
+            ))))),
+        )),
+        DIFFUSE,
+    );
 
     // Light
-    objects.add(Arc::new(XyRect::new(
-        3.0,
-        5.0,
-        -3.0,
-        -1.0,
-        -2.0,
-        Arc::new(DiffuseLight::new(Arc::new(SolidColor::new(Color::new(
-            4.0, 4.0, 4.0,
-        ))))),
-    )));
-    objects.add(Arc::new(XzRect::new(
-        -1.0,
-        5.0,
-        -2.0,
-        4.0,
-        -4.0,
-        Arc::new(DiffuseLight::new(Arc::new(SolidColor::new(Color::new(
-            4.0, 4.0, 4.0,
-        ))))),
-    )));
+    add(
+        Arc::new(XyRect::new(
+            3.0,
+            5.0,
+            -3.0,
+            -1.0,
+            -2.0,
+            Arc::new(DiffuseLight::new(Arc::new(SolidColor::new(Color::new(
+                4.0, 4.0, 4.0,
+            ))))),
+        )),
+        LIGHT,
+    );
+    add(
+        Arc::new(XzRect::new(
+            -1.0,
+            5.0,
+            -2.0,
+            4.0,
+            -4.0,
+            Arc::new(DiffuseLight::with_group(
+                Arc::new(SolidColor::new(Color::new(4.0, 4.0, 4.0))),
+                1,
+            )),
+        )),
+        LIGHT,
+    );
 
     objects
 }
@@ -131,6 +173,14 @@ pub fn final_scene() -> HittableList {
     let mut objects = HittableList::new();
     let mut boxes = HittableList::new();
 
+    // one unit box shared by every instance, scaled and moved into place
+    let ground = Arc::new(Lambertian::new(Arc::new(SolidColor::new(Color::new(
+        0.48, 0.83, 0.53,
+    )))));
+    let unit_box: Arc<dyn Hittable> = Arc::new(LinearBvh::new(
+        Box::new(Point3::zero(), Point3::ones(), ground).sides,
+    ));
+
     let boxes_per_side = 10;
     for i in 0..boxes_per_side {
         for j in 0..boxes_per_side {
@@ -138,50 +188,62 @@ pub fn final_scene() -> HittableList {
             let x0 = -500.0 + i as f64 * w;
             let z0 = -500.0 + j as f64 * w;
             let y0 = 0.0;
-            let x1 = x0 + w;
             let y1 = random_f64_range(1.0, 101.0);
-            let z1 = z0 + w;
-
-            boxes.add(Arc::new(Box::new(
-                Point3::new(x0, -y1, z0),
-                Point3::new(x1, -y0, z1),
-                Arc::new(Lambertian::new(Arc::new(SolidColor::new(Color::new(
-                    0.48, 0.83, 0.53,
-                ))))),
+
+            boxes.add(Arc::new(Instance::new(
+                unit_box.clone(),
+                Transform::scale(Vec3::new(w, y1 - y0, w))
+                    .then(&Transform::translate(Point3::new(x0, -y1, z0))),
+                None,
             )));
         }
     }
 
-    objects.add(Arc::new(BvhNode::new(&mut boxes)));
-
-    objects.add(Arc::new(XzRect::new(
-        123.0,
-        423.0,
-        147.0,
-        412.0,
-        -554.0,
-        Arc::new(DiffuseLight::new(Arc::new(SolidColor::new(Color::new(
-            7.0, 7.0, 7.0,
-        ))))),
-    )));
+    let mut add = |object: Arc<dyn Hittable>, material_id: u32| {
+        objects.add(Arc::new(Tagged::material(object, material_id)));
+    };
+    add(Arc::new(LinearBvh::new(boxes)), GROUND);
+
+    add(
+        Arc::new(XzRect::new(
+            123.0,
+            423.0,
+            147.0,
+            412.0,
+            -554.0,
+            Arc::new(DiffuseLight::new(Arc::new(SolidColor::new(Color::new(
+                7.0, 7.0, 7.0,
+            ))))),
+        )),
+        LIGHT,
+    );
 
     let albedo: Color = Color::random().elemul(Color::random());
-    objects.add(Arc::new(Sphere::new(
-        Point3::new(260.0, -150.0, 45.0),
-        50.0,
-        Arc::new(Lambertian::new(Arc::new(SolidColor::new(albedo)))),
-    )));
-    objects.add(Arc::new(Sphere::new(
-        Point3::new(0.0, -150.0, 145.0),
-        50.0,
-        Arc::new(Metal::new(Color::new(0.8, 0.8, 0.9), 10.0)),
-    )));
+    add(
+        Arc::new(Sphere::new(
+            Point3::new(260.0, -150.0, 45.0),
+            50.0,
+            Arc::new(Lambertian::new(Arc::new(SolidColor::new(albedo)))),
+        )),
+        DIFFUSE,
+    );
+    add(
+        Arc::new(Sphere::new(
+            Point3::new(0.0, -150.0, 145.0),
+            50.0,
+            Arc::new(Metal::new(Color::new(0.8, 0.8, 0.9), 10.0)),
+        )),
+        METAL,
+    );
 
     objects
 }
 
 pub fn maiden_room() -> HittableList {
     let mut room = HittableList::new();
+    let mut add = |object: Arc<dyn Hittable>, material_id: u32| {
+        room.add(Arc::new(Tagged::material(object, material_id)));
+    };
 
     // Ground
 
@@ -190,11 +252,14 @@ pub fn maiden_room() -> HittableList {
     let ground_material = Arc::new(Lambertian::new(Arc::new(CheckerTexture::new(
         misty_rose, light_gray,
     ))));
-    room.add(Arc::new(Sphere::new(
-        Point3::new(0.0, 1500.0, 0.0),
-        1500.0,
-        ground_material,
-    )));
+    add(
+        Arc::new(Sphere::new(
+            Point3::new(0.0, 1500.0, 0.0),
+            1500.0,
+            ground_material,
+        )),
+        GROUND,
+    );
 
     // Spheres
 
@@ -214,26 +279,31 @@ pub fn maiden_room() -> HittableList {
 
             if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
                 if choose_mat < 0.1 {
-                    room.add(Arc::new(Sphere::new(
-                        center,
-                        radius,
-                        Arc::new(Dielectric::new(0.5)),
-                    )));
+                    add(
+                        Arc::new(Sphere::new(center, radius, Arc::new(Dielectric::new(0.5)))),
+                        GLASS,
+                    );
                 } else if choose_mat < 0.75 {
                     let albedo: Color = Color::ones() - Color::random().elemul(Color::random());
-                    room.add(Arc::new(Sphere::new(
-                        center,
-                        radius,
-                        Arc::new(Lambertian::new(Arc::new(SolidColor::new(albedo)))),
-                    )));
+                    add(
+                        Arc::new(Sphere::new(
+                            center,
+                            radius,
+                            Arc::new(Lambertian::new(Arc::new(SolidColor::new(albedo)))),
+                        )),
+                        DIFFUSE,
+                    );
                 } else {
                     let albedo: Color = Color::random_range(0.5, 1.0);
                     let fuzz: f64 = random_f64_range(0.0, 0.5);
-                    room.add(Arc::new(Sphere::new(
-                        center,
-                        radius,
-                        Arc::new(Metal::new(albedo, fuzz)),
-                    )));
+                    add(
+                        Arc::new(Sphere::new(
+                            center,
+                            radius,
+                            Arc::new(Metal::new(albedo, fuzz)),
+                        )),
+                        METAL,
+                    );
                 }
             }
         }
@@ -294,12 +364,12 @@ pub fn maiden_room() -> HittableList {
         )))),
     );
 
-    room.add(Arc::new(RotateY::new(Arc::new(box1), 90.0)));
-    room.add(Arc::new(RotateY::new(Arc::new(box2), 45.0)));
-    room.add(Arc::new(RotateY::new(Arc::new(box3), 90.0)));
-    room.add(Arc::new(RotateY::new(Arc::new(box4), 45.0)));
-    room.add(Arc::new(RotateY::new(Arc::new(box5), 45.0)));
-    room.add(Arc::new(sphere1));
+    add(Arc::new(RotateY::new(Arc::new(box1), 90.0)), TOWER);
+    add(Arc::new(RotateY::new(Arc::new(box2), 45.0)), TOWER);
+    add(Arc::new(RotateY::new(Arc::new(box3), 90.0)), TOWER);
+    add(Arc::new(RotateY::new(Arc::new(box4), 45.0)), TOWER);
+    add(Arc::new(RotateY::new(Arc::new(box5), 45.0)), TOWER);
+    add(Arc::new(sphere1), TOWER);
 
     room
 }
@@ -315,3 +385,54 @@ fn gradient_ramp(c1: Color, c2: Color, num: u32, step: u32) -> Color {
         c1.z + z_tolerance * step as f64,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::HitRecord;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_every_scene_has_material_ids() {
+        let scenes: Vec<(HittableList, Point3, Point3, f64)> = vec![
+            (
+                random_scene(),
+                Point3::new(13.0, -2.0, 3.0),
+                Point3::zero(),
+                3.0,
+            ),
+            (
+                simple_light(),
+                Point3::new(26.0, -3.0, 6.0),
+                Point3::new(0.0, -2.0, 0.0),
+                4.0,
+            ),
+            (
+                final_scene(),
+                Point3::new(478.0, -278.0, -600.0),
+                Point3::new(278.0, -278.0, 0.0),
+                250.0,
+            ),
+            (
+                maiden_room(),
+                Point3::new(26.0, -26.0, 6.0),
+                Point3::new(0.0, -2.3, 0.0),
+                4.0,
+            ),
+        ];
+        let mut rec = HitRecord::new(Arc::new(Lambertian::new(Arc::new(SolidColor::new(
+            Color::zero(),
+        )))));
+        for (world, lookfrom, lookat, spread) in scenes {
+            let mut hits = 0;
+            for _ in 0..200 {
+                let target = lookat + Vec3::random_range(-spread, spread);
+                if world.hit(Ray::new(lookfrom, target - lookfrom), 0.001, INF, &mut rec) {
+                    assert_ne!(rec.material_id, 0);
+                    hits += 1;
+                }
+            }
+            assert!(hits > 0);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+use std::sync::{mpsc::channel, Arc, Mutex};
+use std::thread;
+
+// Tile scheduler
+//
+// The image is cut into square tiles which are dealt round-robin to one queue
+// per worker in the chosen order. A worker takes tiles from the front of its
+// own queue and, once that runs dry, steals from the back of the others, so a
+// few expensive tiles can't leave the remaining threads idle.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub index: usize,
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32, // exclusive
+    pub y1: u32, // exclusive
+}
+
+impl Tile {
+    pub fn width(&self) -> u32 {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> u32 {
+        self.y1 - self.y0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,  // from the center of the image outwards
+    Hilbert, // along a Hilbert curve, neighbouring tiles are rendered together
+}
+
+pub fn make_tiles(width: u32, height: u32, tile_size: u32, order: TileOrder) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let nx = (0..width).step_by(tile_size as usize).count() as u32;
+    let ny = (0..height).step_by(tile_size as usize).count() as u32;
+
+    let mut cells: Vec<(u32, u32)> = Vec::with_capacity((nx * ny) as usize);
+    for ty in 0..ny {
+        for tx in 0..nx {
+            cells.push((tx, ty));
+        }
+    }
+
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => {
+            // ring by ring around the center, each ring walked by angle
+            let cx = (nx as f64 - 1.0) / 2.0;
+            let cy = (ny as f64 - 1.0) / 2.0;
+            let key = |&(tx, ty): &(u32, u32)| {
+                let dx = tx as f64 - cx;
+                let dy = ty as f64 - cy;
+                (dx.abs().max(dy.abs()), dy.atan2(dx))
+            };
+            cells.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        }
+        TileOrder::Hilbert => {
+            let n = nx.max(ny).next_power_of_two();
+            cells.sort_by_key(|&(tx, ty)| hilbert_index(n, tx, ty));
+        }
+    }
+
+    cells
+        .into_iter()
+        .enumerate()
+        .map(|(index, (tx, ty))| Tile {
+            index,
+            x0: tx * tile_size,
+            y0: ty * tile_size,
+            x1: ((tx + 1) * tile_size).min(width),
+            y1: ((ty + 1) * tile_size).min(height),
+        })
+        .collect()
+}
+
+// distance of (x, y) along the Hilbert curve filling an n * n grid
+fn hilbert_index(n: u32, x: u32, y: u32) -> u64 {
+    let (mut x, mut y) = (x, y);
+    let mut d: u64 = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        d += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+        // rotate the quadrant so that the curve stays continuous
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+
+    d
+}
+
+pub struct TileQueue {
+    queues: Vec<Mutex<VecDeque<Tile>>>,
+}
+
+impl TileQueue {
+    pub fn new(tiles: Vec<Tile>, n_workers: usize) -> Self {
+        let n_workers = n_workers.max(1);
+        let mut queues: Vec<VecDeque<Tile>> = (0..n_workers).map(|_| VecDeque::new()).collect();
+        for (i, tile) in tiles.into_iter().enumerate() {
+            queues[i % n_workers].push_back(tile);
+        }
+
+        Self {
+            queues: queues.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    pub fn next(&self, worker: usize) -> Option<Tile> {
+        if let Some(tile) = self.queues[worker].lock().unwrap().pop_front() {
+            return Some(tile);
+        }
+
+        // steal the tile the victim would have reached last
+        let n = self.queues.len();
+        (1..n).find_map(|k| self.queues[(worker + k) % n].lock().unwrap().pop_back())
+    }
+}
+
+// Runs `work` on every tile using `n_workers` threads and hands each result to
+// `on_done` on the calling thread as soon as it arrives.
+pub fn run<T, F, G>(tiles: Vec<Tile>, n_workers: usize, work: F, mut on_done: G)
+where
+    T: Send + 'static,
+    F: Fn(&Tile) -> T + Send + Sync + 'static,
+    G: FnMut(Tile, T),
+{
+    let n_workers = n_workers.max(1);
+    let n_tiles = tiles.len();
+    let queue = Arc::new(TileQueue::new(tiles, n_workers));
+    let work = Arc::new(work);
+    let (tx, rx) = channel();
+
+    let handles: Vec<_> = (0..n_workers)
+        .map(|worker| {
+            let queue = queue.clone();
+            let work = work.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                while let Some(tile) = queue.next(worker) {
+                    let result = work(&tile);
+                    tx.send((tile, result)).expect("failed to send result");
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+
+    for (tile, result) in rx.iter().take(n_tiles) {
+        on_done(tile, result);
+    }
+    for handle in handles {
+        handle.join().expect("render worker panicked");
+    }
+}
+
+pub fn default_workers() -> usize {
+    num_cpus::get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_covers(tiles: &[Tile], width: u32, height: u32) {
+        let mut covered = vec![0; (width * height) as usize];
+        for tile in tiles {
+            for y in tile.y0..tile.y1 {
+                for x in tile.x0..tile.x1 {
+                    covered[(y * width + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn test_make_tiles() {
+        for &order in &[TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            let tiles = make_tiles(100, 37, 16, order);
+            assert_eq!(tiles.len(), 7 * 3);
+            assert_covers(&tiles, 100, 37);
+            for (i, tile) in tiles.iter().enumerate() {
+                assert_eq!(tile.index, i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_spiral_starts_in_center() {
+        let tiles = make_tiles(90, 90, 30, TileOrder::Spiral);
+        assert_eq!((tiles[0].x0, tiles[0].y0), (30, 30));
+    }
+
+    #[test]
+    fn test_hilbert_is_continuous() {
+        let tiles = make_tiles(128, 128, 16, TileOrder::Hilbert);
+        for pair in tiles.windows(2) {
+            let dx = (pair[0].x0 as i32 - pair[1].x0 as i32).abs();
+            let dy = (pair[0].y0 as i32 - pair[1].y0 as i32).abs();
+            assert_eq!(dx + dy, 16);
+        }
+    }
+
+    #[test]
+    fn test_queue_steals_from_the_back() {
+        let queue = TileQueue::new(make_tiles(24, 24, 8, TileOrder::Scanline), 3);
+        let mut taken = Vec::new();
+        while let Some(tile) = queue.next(1) {
+            taken.push(tile.index);
+        }
+        // its own tiles first, then the ends of worker 2's and worker 0's queues
+        assert_eq!(taken, vec![1, 4, 7, 8, 5, 2, 6, 3, 0]);
+        assert_eq!(queue.next(0), None);
+    }
+
+    #[test]
+    fn test_run_steals_all_tiles() {
+        // the tiles dealt to worker 0 are slow, so the others run out of work
+        // and have to take some of them
+        let tiles = make_tiles(64, 64, 8, TileOrder::Scanline);
+        let mut done = vec![false; tiles.len()];
+        let mut slow_threads = Vec::new();
+        run(
+            tiles,
+            3,
+            |tile| {
+                if tile.index % 3 == 0 {
+                    thread::sleep(std::time::Duration::from_millis(20));
+                }
+                (tile.index, thread::current().id())
+            },
+            |tile, (index, thread)| {
+                assert_eq!(tile.index, index);
+                done[index] = true;
+                if index % 3 == 0 && !slow_threads.contains(&thread) {
+                    slow_threads.push(thread);
+                }
+            },
+        );
+        assert!(done.iter().all(|&d| d));
+        assert!(slow_threads.len() > 1);
+    }
+}
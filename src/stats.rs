@@ -0,0 +1,214 @@
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Render statistics
+//
+// Every thread counts the work it does into its own counters, which are only
+// added to the shared totals once a tile is done, so the hot loops never touch
+// memory another thread writes to. Traversals count in locals and record them
+// once per ray. The totals cover everything rendered since the program
+// started.
+//
+// Node visits and primitive tests are counted by the flattened BVHs
+// (LinearBvh and Bvh4) only. A list in a leaf, like the sides of a box, is one
+// primitive test, and nothing is counted for a world without a BVH. The
+// bottom-level BVHs of instances count on top of the top level.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub camera_rays: u64,
+    // camera rays and every bounce after them
+    pub rays: u64,
+    // BVH nodes whose bounding box was tested
+    pub node_visits: u64,
+    // objects tested from a BVH leaf, or from its list of unbounded objects
+    pub primitive_tests: u64,
+}
+
+impl Counters {
+    // rays per camera ray, that is the bounces of an average path
+    pub fn average_path_length(&self) -> f64 {
+        if self.camera_rays == 0 {
+            0.0
+        } else {
+            self.rays as f64 / self.camera_rays as f64
+        }
+    }
+}
+
+thread_local! {
+    static LOCAL: Cell<Counters> = Cell::new(Counters::default());
+}
+
+static CAMERA_RAYS: AtomicU64 = AtomicU64::new(0);
+static RAYS: AtomicU64 = AtomicU64::new(0);
+static NODE_VISITS: AtomicU64 = AtomicU64::new(0);
+static PRIMITIVE_TESTS: AtomicU64 = AtomicU64::new(0);
+
+// adds to the counters of this thread
+pub fn record(f: impl FnOnce(&mut Counters)) {
+    LOCAL.with(|local| {
+        let mut counters = local.get();
+        f(&mut counters);
+        local.set(counters);
+    });
+}
+
+// moves the counters of this thread into the totals
+pub fn flush() {
+    let counters = LOCAL.with(|local| local.replace(Counters::default()));
+    CAMERA_RAYS.fetch_add(counters.camera_rays, Ordering::Relaxed);
+    RAYS.fetch_add(counters.rays, Ordering::Relaxed);
+    NODE_VISITS.fetch_add(counters.node_visits, Ordering::Relaxed);
+    PRIMITIVE_TESTS.fetch_add(counters.primitive_tests, Ordering::Relaxed);
+}
+
+pub fn totals() -> Counters {
+    Counters {
+        camera_rays: CAMERA_RAYS.load(Ordering::Relaxed),
+        rays: RAYS.load(Ordering::Relaxed),
+        node_visits: NODE_VISITS.load(Ordering::Relaxed),
+        primitive_tests: PRIMITIVE_TESTS.load(Ordering::Relaxed),
+    }
+}
+
+// the counters of a run along with how long each of its phases took
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub commit: Option<String>,
+    pub counters: Counters,
+    pub phases: Vec<(String, Duration)>,
+}
+
+impl Report {
+    // phases that run more than once, like the render of every frame of a
+    // sequence, add up
+    pub fn add_phase(&mut self, name: &str, time: Duration) {
+        match self.phases.iter_mut().find(|(n, _)| n == name) {
+            Some((_, total)) => *total += time,
+            None => self.phases.push((name.to_owned(), time)),
+        }
+    }
+
+    fn phase(&self, name: &str) -> Option<Duration> {
+        self.phases.iter().find(|(n, _)| n == name).map(|&(_, t)| t)
+    }
+
+    // rays traced per second of the render phase
+    pub fn rays_per_second(&self) -> Option<f64> {
+        self.phase("render")
+            .filter(|t| *t > Duration::from_secs(0))
+            .map(|t| self.counters.rays as f64 / t.as_secs_f64())
+    }
+
+    pub fn print(&self) {
+        let c = &self.counters;
+        let per_ray = |n: u64| n as f64 / c.rays.max(1) as f64;
+        println!("Statistics");
+        println!("  camera rays          {}", c.camera_rays);
+        println!("  rays                 {}", c.rays);
+        println!(
+            "  BVH node visits      {} ({:.1} per ray)",
+            c.node_visits,
+            per_ray(c.node_visits)
+        );
+        println!(
+            "  primitive tests      {} ({:.1} per ray)",
+            c.primitive_tests,
+            per_ray(c.primitive_tests)
+        );
+        println!("  average path length  {:.2}", c.average_path_length());
+        if let Some(rate) = self.rays_per_second() {
+            println!("  rays per second      {:.0}", rate);
+        }
+        for (name, time) in &self.phases {
+            println!("  {:<20} {:.3}s", name, time.as_secs_f64());
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let c = &self.counters;
+        let commit = match &self.commit {
+            Some(commit) => format!("\"{}\"", commit),
+            None => "null".to_owned(),
+        };
+        let phases: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(name, time)| format!("\"{}\": {:.6}", name, time.as_secs_f64()))
+            .collect();
+        let mut fields = vec![
+            format!("\"commit\": {}", commit),
+            format!("\"camera_rays\": {}", c.camera_rays),
+            format!("\"rays\": {}", c.rays),
+            format!("\"bvh_node_visits\": {}", c.node_visits),
+            format!("\"primitive_tests\": {}", c.primitive_tests),
+            format!("\"average_path_length\": {:.6}", c.average_path_length()),
+        ];
+        if let Some(rate) = self.rays_per_second() {
+            fields.push(format!("\"rays_per_second\": {:.1}", rate));
+        }
+        fields.push(format!("\"phases\": {{{}}}", phases.join(", ")));
+        format!("{{\n  {}\n}}\n", fields.join(",\n  "))
+    }
+
+    pub fn save_json(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_counters_reach_totals_on_flush() {
+        // other tests may be rendering at the same time, so only look at
+        // what this thread adds
+        thread::spawn(|| {
+            let before = totals();
+            record(|c| {
+                c.camera_rays += 10;
+                c.rays += 35;
+            });
+            assert_eq!(LOCAL.with(|l| l.get().rays), 35);
+            flush();
+            let after = totals();
+            assert!(after.camera_rays - before.camera_rays >= 10);
+            assert!(after.rays - before.rays >= 35);
+            assert_eq!(LOCAL.with(|l| l.get()), Counters::default());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = Report {
+            commit: None,
+            counters: Counters {
+                camera_rays: 100,
+                rays: 250,
+                node_visits: 4000,
+                primitive_tests: 900,
+            },
+            phases: Vec::new(),
+        };
+        report.add_phase("scene", Duration::from_millis(5));
+        report.add_phase("render", Duration::from_secs(1));
+        report.add_phase("render", Duration::from_secs(1));
+        assert_eq!(report.counters.average_path_length(), 2.5);
+        assert_eq!(report.rays_per_second(), Some(125.0));
+
+        let json = report.to_json();
+        assert!(json.contains("\"commit\": null,"));
+        assert!(json.contains("\"bvh_node_visits\": 4000,"));
+        assert!(json.contains("\"average_path_length\": 2.500000,"));
+        assert!(json.contains("\"phases\": {\"scene\": 0.005000, \"render\": 2.000000}"));
+        assert_eq!(Counters::default().average_path_length(), 0.0);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::camera::{Camera, CameraModel, Equirectangular};
+use crate::ray::Ray;
+use crate::utils::PI;
+use crate::vec3::{Point3, Vec3};
+use std::sync::Arc;
+
+// Stereo
+//
+// Renders a view for each eye into one image, the left eye on the left or on
+// top. The eyes sit `ipd` apart along the camera's right axis. Perspective
+// eyes keep parallel view directions and shift their image windows instead of
+// turning inwards, so that both frame the same rectangle at the convergence
+// distance; objects there appear at the depth of the screen, nearer ones in
+// front of it. Omni-directional stereo (ODS) panoramas move the eyes around a
+// circle instead, so every direction of an equirectangular image is seen with
+// the eyes side by side.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StereoLayout {
+    SideBySide,
+    OverUnder,
+}
+
+impl StereoLayout {
+    // the shape of the whole image for eyes of `eye_aspect_ratio`
+    pub fn aspect_ratio(&self, eye_aspect_ratio: f64) -> f64 {
+        match self {
+            StereoLayout::SideBySide => eye_aspect_ratio * 2.0,
+            StereoLayout::OverUnder => eye_aspect_ratio / 2.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StereoSettings {
+    // interpupillary distance, in world units
+    pub ipd: f64,
+    // distance of the zero parallax plane, None for parallel eyes that put
+    // everything in front of the screen
+    pub convergence: Option<f64>,
+    pub layout: StereoLayout,
+}
+
+pub struct Stereo {
+    left: Arc<dyn CameraModel>,
+    right: Arc<dyn CameraModel>,
+    layout: StereoLayout,
+}
+
+impl Stereo {
+    pub fn new(
+        left: Arc<dyn CameraModel>,
+        right: Arc<dyn CameraModel>,
+        layout: StereoLayout,
+    ) -> Self {
+        Self {
+            left,
+            right,
+            layout,
+        }
+    }
+
+    // the eyes of `camera`, which is at the middle between them
+    pub fn perspective(camera: &Camera, settings: &StereoSettings) -> Self {
+        let eye = |side: f64| -> Arc<dyn CameraModel> {
+            Arc::new(camera.eye(side * settings.ipd / 2.0, settings.convergence))
+        };
+
+        Self::new(eye(-1.0), eye(1.0), settings.layout)
+    }
+
+    // an ODS pair of equirectangular panoramas, usually over-under
+    pub fn ods(lookfrom: Point3, lookat: Point3, vup: Vec3, settings: &StereoSettings) -> Self {
+        let panorama = Equirectangular::new(lookfrom, lookat, vup);
+        let eye = |side: f64| -> Arc<dyn CameraModel> {
+            Arc::new(OdsEye {
+                panorama,
+                center: lookfrom,
+                offset: side * settings.ipd / 2.0,
+            })
+        };
+
+        Self::new(eye(-1.0), eye(1.0), settings.layout)
+    }
+}
+
+impl CameraModel for Stereo {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => self.left.get_ray(s * 2.0, t),
+            StereoLayout::SideBySide => self.right.get_ray(s * 2.0 - 1.0, t),
+            // t grows down the image
+            StereoLayout::OverUnder if t < 0.5 => self.left.get_ray(s, t * 2.0),
+            StereoLayout::OverUnder => self.right.get_ray(s, t * 2.0 - 1.0),
+        }
+    }
+}
+
+// One eye of an ODS panorama: the ray seen at (s, t) starts `offset` to the
+// right of the center, across the direction it looks in. Towards the poles
+// the eyes move in, or looking up and down would show a circle of eyes.
+struct OdsEye {
+    panorama: Equirectangular,
+    center: Point3,
+    offset: f64,
+}
+
+impl CameraModel for OdsEye {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        let latitude = (t - 0.5) * PI;
+        let origin = self.center + self.panorama.right(s) * (self.offset * latitude.cos());
+        Some(Ray::new(origin, self.panorama.direction(s, t)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_perspective_eyes_converge() {
+        let lookat = Point3::new(0.0, 0.0, -5.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+        let settings = StereoSettings {
+            ipd: 0.2,
+            convergence: Some(5.0),
+            layout: StereoLayout::SideBySide,
+        };
+        let camera = Camera::new(Point3::zero(), lookat, vup, 40.0, 1.0, 0.0, 10.0);
+        let stereo = Stereo::perspective(&camera, &settings);
+
+        // both eyes see lookat in the middle of their half of the image
+        let left = stereo.get_ray(0.25, 0.5).unwrap();
+        let right = stereo.get_ray(0.75, 0.5).unwrap();
+        assert_close(left.origin, Point3::new(-0.1, 0.0, 0.0));
+        assert_close(right.origin, Point3::new(0.1, 0.0, 0.0));
+        assert_close(left.at(5.0 / -left.direction.z), lookat);
+        assert_close(right.at(5.0 / -right.direction.z), lookat);
+
+        let parallel = Stereo::perspective(
+            &camera,
+            &StereoSettings {
+                convergence: None,
+                layout: StereoLayout::OverUnder,
+                ..settings
+            },
+        );
+        let top = parallel.get_ray(0.5, 0.25).unwrap();
+        assert_close(top.origin, Point3::new(-0.1, 0.0, 0.0));
+        assert_close(top.direction.unit(), Vec3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn test_ods_eyes_circle_the_center() {
+        let settings = StereoSettings {
+            ipd: 0.2,
+            convergence: None,
+            layout: StereoLayout::OverUnder,
+        };
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+        let ods = Stereo::ods(Point3::zero(), Point3::new(0.0, 0.0, -1.0), vup, &settings);
+
+        // looking forward the left eye is on the left, looking right it is in front
+        let forward = ods.get_ray(0.5, 0.25).unwrap();
+        assert_close(forward.origin, Point3::new(-0.1, 0.0, 0.0));
+        let right = ods.get_ray(0.75, 0.25).unwrap();
+        assert_close(right.origin, Point3::new(0.0, 0.0, -0.1));
+        assert_close(right.direction, Vec3::new(1.0, 0.0, 0.0));
+        // and both eyes meet at the poles
+        let pole = ods.get_ray(0.3, 0.5).unwrap();
+        assert_close(pole.origin, Point3::zero());
+    }
+}
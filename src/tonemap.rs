@@ -0,0 +1,267 @@
+use crate::film;
+use crate::utils::*;
+use crate::vec3::Color;
+use image::Rgb;
+
+// Tone mapping
+//
+// Turns linear radiance into 8-bit display values for LDR formats: scale by
+// the exposure, compress the range with an operator, encode with a transfer
+// function and quantize, optionally with dithering. The default reproduces
+// the original conversion, a square root with hard clipping.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMap {
+    Clamp,            // no compression, everything above 1 clips
+    Reinhard,         // L / (1 + L) on luminance
+    ExtendedReinhard, // Reinhard reaching 1 at the white point
+    Aces,             // Narkowicz's fit of the ACES filmic curve
+    Agx,              // a log-space sigmoid after Troy Sobotka's AgX
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transfer {
+    Gamma2, // square root, as before
+    Srgb,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneMapping {
+    // in stops, every +1 doubles the radiance
+    pub exposure: f64,
+    pub operator: ToneMap,
+    // The luminance shown as white. Extended Reinhard uses it directly, the
+    // other operators are scaled so that it maps to 1. Without it, extended
+    // Reinhard is plain Reinhard and the others are left as they are.
+    pub white_point: Option<f64>,
+    pub transfer: Transfer,
+    // adds triangular noise of one step before quantizing, which breaks up
+    // banding in smooth gradients
+    pub dither: bool,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            operator: ToneMap::Clamp,
+            white_point: None,
+            transfer: Transfer::Gamma2,
+            dither: false,
+        }
+    }
+}
+
+impl ToneMapping {
+    // display value of linear radiance `c` at pixel (x, y)
+    pub fn apply(&self, c: Color, x: u32, y: u32) -> Rgb<u8> {
+        let c = c * 2f64.powf(self.exposure);
+        let mapped = match self.white_point {
+            Some(white) if self.operator != ToneMap::ExtendedReinhard => {
+                self.map(c) / self.map(Color::ones() * white).y
+            }
+            _ => self.map(c),
+        };
+
+        let mut rgb = [0u8; 3];
+        for (i, &v) in [mapped.x, mapped.y, mapped.z].iter().enumerate() {
+            let encoded = match self.transfer {
+                Transfer::Gamma2 => clamp(v, 0.0, 1.0).sqrt(),
+                Transfer::Srgb => srgb_encode(clamp(v, 0.0, 1.0)),
+            };
+            let noise = if self.dither {
+                triangular_noise(x, y, i as u32) / 256.0
+            } else {
+                0.0
+            };
+            rgb[i] = (clamp(encoded + noise, 0.0, 0.999) * 256.0) as u8;
+        }
+
+        Rgb(rgb)
+    }
+
+    // the operator alone, from linear radiance to linear display values
+    fn map(&self, c: Color) -> Color {
+        match self.operator {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => scale_luminance(c, |l| l / (1.0 + l)),
+            ToneMap::ExtendedReinhard => {
+                let white = self.white_point.unwrap_or(INF);
+                scale_luminance(c, |l| l * (1.0 + l / (white * white)) / (1.0 + l))
+            }
+            ToneMap::Aces => {
+                let f = |x: f64| {
+                    let x = x.max(0.0);
+                    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+                };
+                Color::new(f(c.x), f(c.y), f(c.z))
+            }
+            ToneMap::Agx => agx(c),
+        }
+    }
+}
+
+// applies `f` to the luminance of `c`, keeping its hue
+fn scale_luminance(c: Color, f: impl Fn(f64) -> f64) -> Color {
+    let l = film::luminance(c);
+    if l <= 0.0 {
+        return Color::zero();
+    }
+    c * (f(l) / l)
+}
+
+pub fn srgb_encode(v: f64) -> f64 {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// in (-1, 1) with a triangular distribution, fixed per pixel and channel so
+// that snapshots of the same film are identical
+fn triangular_noise(x: u32, y: u32, channel: u32) -> f64 {
+    let h = derive_seed(((y as u64) << 32) | x as u64, channel as u64);
+    let a = (h >> 40) as f64 / (1u64 << 24) as f64;
+    let b = (h & 0xff_ffff) as f64 / (1u64 << 24) as f64;
+    a + b - 1.0
+}
+
+// Minimal AgX: squeeze the primaries inwards, encode in log2 around middle
+// gray, apply a sigmoid fitted to the AgX base contrast, then undo the squeeze
+// and the sigmoid's built-in display gamma of 2.2.
+fn agx(c: Color) -> Color {
+    const INSET: [[f64; 3]; 3] = [
+        [
+            0.842_479_062_253_094,
+            0.042_328_242_261_012_3,
+            0.042_375_654_905_705_1,
+        ],
+        [0.078_433_599_999_999_2, 0.878_468_636_469_772, 0.078_433_6],
+        [
+            0.079_223_745_147_764_3,
+            0.079_166_127_460_543_4,
+            0.879_142_973_793_104,
+        ],
+    ];
+    const OUTSET: [[f64; 3]; 3] = [
+        [
+            1.196_879_005_120_17,
+            -0.052_896_851_757_456_2,
+            -0.052_971_635_514_443_8,
+        ],
+        [
+            -0.098_020_881_140_136_8,
+            1.151_903_129_904_17,
+            -0.098_043_450_117_124_1,
+        ],
+        [
+            -0.099_029_744_079_720_5,
+            -0.098_961_176_844_843_3,
+            1.151_073_672_641_16,
+        ],
+    ];
+    const MIN_EV: f64 = -12.473_93;
+    const MAX_EV: f64 = 4.026_069;
+
+    // the rows above are the columns of the matrices
+    let mul = |m: &[[f64; 3]; 3], v: Color| {
+        Color::new(m[0][0], m[0][1], m[0][2]) * v.x
+            + Color::new(m[1][0], m[1][1], m[1][2]) * v.y
+            + Color::new(m[2][0], m[2][1], m[2][2]) * v.z
+    };
+    let contrast = |v: f64| {
+        let x = (clamp(v.max(1e-10).log2(), MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.002_32
+    };
+
+    let v = mul(&INSET, c);
+    let v = mul(
+        &OUTSET,
+        Color::new(contrast(v.x), contrast(v.y), contrast(v.z)),
+    );
+    let linear = |x: f64| x.max(0.0).powf(2.2);
+    Color::new(linear(v.x), linear(v.y), linear(v.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with(operator: ToneMap) -> ToneMapping {
+        ToneMapping {
+            operator,
+            ..ToneMapping::default()
+        }
+    }
+
+    #[test]
+    fn test_default_matches_gamma2_clamp() {
+        let tone = ToneMapping::default();
+        for &v in &[0.0, 0.01, 0.25, 0.5, 0.999, 1.0, 3.0] {
+            let expected = (clamp(f64::sqrt(v), 0.0, 0.999) * 256.0) as u8;
+            assert_eq!(tone.apply(Color::ones() * v, 0, 0), Rgb([expected; 3]));
+        }
+    }
+
+    #[test]
+    fn test_srgb_encode() {
+        assert_eq!(srgb_encode(0.0), 0.0);
+        assert!((srgb_encode(1.0) - 1.0).abs() < 1e-12);
+        assert!((srgb_encode(0.18) - 0.461).abs() < 1e-3);
+        // the linear and the power segment meet
+        let t = 0.003_130_8;
+        assert!((srgb_encode(t) - srgb_encode(t + 1e-9)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_operators_compress_monotonically() {
+        for &operator in &[ToneMap::Reinhard, ToneMap::Aces, ToneMap::Agx] {
+            let tone = with(operator);
+            let mut last = 0.0;
+            // up to where AgX's log encoding saturates; the ACES fit slightly
+            // overshoots 1
+            for i in 1..150 {
+                let v = tone.map(Color::ones() * (i as f64 * 0.1)).y;
+                assert!(v > last && v < 1.05, "{:?} at {}", operator, i);
+                last = v;
+            }
+        }
+    }
+
+    #[test]
+    fn test_white_point() {
+        for &operator in &[ToneMap::ExtendedReinhard, ToneMap::Reinhard, ToneMap::Aces] {
+            let tone = ToneMapping {
+                white_point: Some(4.0),
+                ..with(operator)
+            };
+            assert_eq!(tone.apply(Color::ones() * 4.0, 0, 0), Rgb([255; 3]));
+            assert!(tone.apply(Color::ones() * 3.0, 0, 0).0[0] < 255);
+        }
+    }
+
+    #[test]
+    fn test_dither_keeps_average() {
+        let tone = ToneMapping {
+            transfer: Transfer::Srgb,
+            dither: true,
+            ..ToneMapping::default()
+        };
+        let c = Color::ones() * 0.2;
+        let exact = srgb_encode(0.2) * 256.0;
+        let mut sum = 0.0;
+        for y in 0..64 {
+            for x in 0..64 {
+                let v = tone.apply(c, x, y).0[1] as f64 + 0.5;
+                assert!((v - exact).abs() <= 2.0);
+                sum += v;
+            }
+        }
+        assert!((sum / 4096.0 - exact).abs() < 0.05);
+        assert_eq!(tone.apply(c, 5, 9), tone.apply(c, 5, 9));
+    }
+}
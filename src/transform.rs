@@ -0,0 +1,255 @@
+use crate::aabb::AABB;
+use crate::utils::*;
+use crate::vec3::{Point3, Vec3};
+
+// Affine transform stored as the top three rows of a 4x4 matrix, together with
+// its inverse so that rays can be moved into object space without inverting
+// anything at render time.
+
+type Mat34 = [[f64; 4]; 3];
+
+const IDENTITY: Mat34 = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    m: Mat34,
+    m_inv: Mat34,
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Self {
+            m: IDENTITY,
+            m_inv: IDENTITY,
+        }
+    }
+
+    pub fn translate(delta: Vec3) -> Self {
+        Self {
+            m: [
+                [1.0, 0.0, 0.0, delta.x],
+                [0.0, 1.0, 0.0, delta.y],
+                [0.0, 0.0, 1.0, delta.z],
+            ],
+            m_inv: [
+                [1.0, 0.0, 0.0, -delta.x],
+                [0.0, 1.0, 0.0, -delta.y],
+                [0.0, 0.0, 1.0, -delta.z],
+            ],
+        }
+    }
+
+    pub fn scale(s: Vec3) -> Self {
+        Self {
+            m: [
+                [s.x, 0.0, 0.0, 0.0],
+                [0.0, s.y, 0.0, 0.0],
+                [0.0, 0.0, s.z, 0.0],
+            ],
+            m_inv: [
+                [1.0 / s.x, 0.0, 0.0, 0.0],
+                [0.0, 1.0 / s.y, 0.0, 0.0],
+                [0.0, 0.0, 1.0 / s.z, 0.0],
+            ],
+        }
+    }
+
+    // rotation by `angle` degrees around `axis`, counter-clockwise when looking
+    // down the axis towards the origin
+    pub fn rotate(angle: f64, axis: Vec3) -> Self {
+        let a: Vec3 = axis.unit();
+        let radians: f64 = degrees_to_radians(angle);
+        let sin_theta: f64 = radians.sin();
+        let cos_theta: f64 = radians.cos();
+        let k: f64 = 1.0 - cos_theta;
+
+        let m: Mat34 = [
+            [
+                a.x * a.x * k + cos_theta,
+                a.x * a.y * k - a.z * sin_theta,
+                a.x * a.z * k + a.y * sin_theta,
+                0.0,
+            ],
+            [
+                a.y * a.x * k + a.z * sin_theta,
+                a.y * a.y * k + cos_theta,
+                a.y * a.z * k - a.x * sin_theta,
+                0.0,
+            ],
+            [
+                a.z * a.x * k - a.y * sin_theta,
+                a.z * a.y * k + a.x * sin_theta,
+                a.z * a.z * k + cos_theta,
+                0.0,
+            ],
+        ];
+
+        // a rotation matrix is orthogonal, its inverse is its transpose
+        let mut m_inv: Mat34 = IDENTITY;
+        for (i, row) in m_inv.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().take(3).enumerate() {
+                *value = m[j][i];
+            }
+        }
+
+        Self { m, m_inv }
+    }
+
+    pub fn rotate_x(angle: f64) -> Self {
+        Self::rotate(angle, Vec3::new(1.0, 0.0, 0.0))
+    }
+
+    pub fn rotate_y(angle: f64) -> Self {
+        Self::rotate(angle, Vec3::new(0.0, 1.0, 0.0))
+    }
+
+    pub fn rotate_z(angle: f64) -> Self {
+        Self::rotate(angle, Vec3::new(0.0, 0.0, 1.0))
+    }
+
+    // the transform that applies `self` first and `next` afterwards
+    pub fn then(&self, next: &Transform) -> Self {
+        Self {
+            m: mul(&next.m, &self.m),
+            m_inv: mul(&self.m_inv, &next.m_inv),
+        }
+    }
+
+    pub fn inverse(&self) -> Self {
+        Self {
+            m: self.m_inv,
+            m_inv: self.m,
+        }
+    }
+
+    pub fn point(&self, p: Point3) -> Point3 {
+        apply(&self.m, p, 1.0)
+    }
+
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        apply(&self.m, v, 0.0)
+    }
+
+    // normals transform with the inverse transpose, the result is not normalized
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        let m = &self.m_inv;
+        Vec3::new(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        )
+    }
+
+    pub fn inv_point(&self, p: Point3) -> Point3 {
+        apply(&self.m_inv, p, 1.0)
+    }
+
+    pub fn inv_vector(&self, v: Vec3) -> Vec3 {
+        apply(&self.m_inv, v, 0.0)
+    }
+
+    // box enclosing all eight transformed corners of `bbox`
+    pub fn bbox(&self, bbox: &AABB) -> AABB {
+        let mut min: Point3 = Point3::new(INF, INF, INF);
+        let mut max: Point3 = Point3::new(-INF, -INF, -INF);
+
+        for i in 0..2 {
+            for j in 0..2 {
+                for k in 0..2 {
+                    let corner = Point3::new(
+                        if i == 0 { bbox._min.x } else { bbox._max.x },
+                        if j == 0 { bbox._min.y } else { bbox._max.y },
+                        if k == 0 { bbox._min.z } else { bbox._max.z },
+                    );
+                    let p = self.point(corner);
+
+                    min = Point3::new(fmin(min.x, p.x), fmin(min.y, p.y), fmin(min.z, p.z));
+                    max = Point3::new(fmax(max.x, p.x), fmax(max.y, p.y), fmax(max.z, p.z));
+                }
+            }
+        }
+
+        AABB::new(min, max)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+fn mul(a: &Mat34, b: &Mat34) -> Mat34 {
+    let mut r: Mat34 = [[0.0; 4]; 3];
+    for (i, row) in r.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = a[i][0] * b[0][j] + a[i][1] * b[1][j] + a[i][2] * b[2][j];
+        }
+        row[3] += a[i][3];
+    }
+
+    r
+}
+
+fn apply(m: &Mat34, v: Vec3, w: f64) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z + m[0][3] * w,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z + m[1][3] * w,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z + m[2][3] * w,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_translate() {
+        let t = Transform::translate(Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(t.point(Point3::zero()), Point3::new(1.0, 2.0, 3.0));
+        assert_eq!(t.vector(Vec3::ones()), Vec3::ones());
+    }
+
+    #[test]
+    fn test_rotate_y() {
+        let t = Transform::rotate_y(90.0);
+        assert_near(
+            t.point(Point3::new(1.0, 0.0, 0.0)),
+            Point3::new(0.0, 0.0, -1.0),
+        );
+    }
+
+    #[test]
+    fn test_then_and_inverse() {
+        let t = Transform::scale(Vec3::new(2.0, 3.0, 4.0))
+            .then(&Transform::rotate(30.0, Vec3::new(1.0, 1.0, 0.0)))
+            .then(&Transform::translate(Vec3::new(-5.0, 1.0, 2.0)));
+        let p = Point3::new(0.3, -1.2, 7.0);
+        assert_near(t.inv_point(t.point(p)), p);
+        assert_near(t.inverse().point(t.point(p)), p);
+        assert_near(
+            t.point(p),
+            Transform::translate(Vec3::new(-5.0, 1.0, 2.0)).point(
+                Transform::rotate(30.0, Vec3::new(1.0, 1.0, 0.0))
+                    .point(Transform::scale(Vec3::new(2.0, 3.0, 4.0)).point(p)),
+            ),
+        );
+    }
+
+    #[test]
+    fn test_normal() {
+        // a normal stays perpendicular to a tangent under non-uniform scaling
+        let t = Transform::scale(Vec3::new(1.0, 4.0, 1.0)).then(&Transform::rotate_z(20.0));
+        let n = Vec3::new(1.0, 1.0, 0.0);
+        let tangent = Vec3::new(1.0, -1.0, 0.0);
+        assert!((t.normal(n) * t.vector(tangent)).abs() < 1e-9);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::sampler;
+use std::cell::Cell;
 use std::f64::consts::PI as OtherPI;
 
 pub const INF: f64 = 0xfffffff as f64;
@@ -7,8 +9,53 @@ pub fn degrees_to_radians(degrees: f64) -> f64 {
     degrees * PI / 180.0
 }
 
+// Every thread draws from its own SplitMix64 generator. It starts from
+// entropy, but can be reseeded so that a sequence of random numbers (a scene,
+// a tile of a pass) can be reproduced exactly.
+thread_local! {
+    static RNG_STATE: Cell<u64> = Cell::new(rand::random());
+}
+
+pub fn seed_rng(seed: u64) {
+    RNG_STATE.with(|state| state.set(seed));
+}
+
+pub fn random_u64() -> u64 {
+    RNG_STATE.with(|state| {
+        let s = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(s);
+        mix64(s)
+    })
+}
+
+// the next dimension of the current sample if a sampler is installed on this
+// thread, otherwise a plain random number
 pub fn random_f64() -> f64 {
-    rand::random::<f64>()
+    match sampler::next_1d() {
+        Some(x) => x,
+        // the top 53 bits fill the mantissa, giving a uniform value in [0, 1)
+        None => (random_u64() >> 11) as f64 / (1u64 << 53) as f64,
+    }
+}
+
+// two random numbers meant to be used together as a 2D point
+pub fn random_2d() -> (f64, f64) {
+    sampler::align_2d();
+    let u = random_f64();
+    (u, random_f64())
+}
+
+// SplitMix64 finalizer, scrambles all bits of `x`
+pub fn mix64(x: u64) -> u64 {
+    let mut z = x;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// derives an independent seed from `seed` for the stream identified by `id`
+pub fn derive_seed(seed: u64, id: u64) -> u64 {
+    mix64(seed ^ mix64(id.wrapping_add(0x632b_e59b_d9b4_e019)))
 }
 
 pub fn random_f64_range(min: f64, max: f64) -> f64 {
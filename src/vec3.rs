@@ -210,20 +210,20 @@ impl Div<f64> for Vec3 {
     }
 }
 
+// The mappings below turn a fixed number of random numbers into a point
+// instead of retrying until one lands inside, so that well-distributed input
+// from a sampler stays well distributed.
+
 pub fn random_in_unit_sphere() -> Vec3 {
-    loop {
-        let p = Vec3::random();
-        if p.squared_length() >= 1.0 {
-            continue;
-        }
-        break p;
-    }
+    let direction = random_unit_vector();
+    direction * random_f64().cbrt()
 }
 
 pub fn random_unit_vector() -> Vec3 {
-    let a = random_f64_range(0.0, 2.0 * PI);
-    let z = random_f64_range(-1.0, 1.0);
-    let r = (1.0 - z * z).sqrt();
+    let (u, v) = random_2d();
+    let z = 1.0 - 2.0 * u;
+    let a = 2.0 * PI * v;
+    let r = (1.0 - z * z).max(0.0).sqrt();
     Vec3::new(r * a.cos(), r * a.sin(), z)
 }
 
@@ -238,18 +238,20 @@ pub fn random_in_hemisphere(normal: Vec3) -> Vec3 {
 }
 */
 
+// Shirley and Chiu's concentric mapping from the square to the disk
 pub fn random_in_unit_disk() -> Vec3 {
-    loop {
-        let p = Vec3::new(
-            random_f64_range(-1.0, 1.0),
-            random_f64_range(-1.0, 1.0),
-            0.0,
-        );
-        if p.squared_length() >= 1.0 {
-            continue;
-        }
-        break p;
+    let (u, v) = random_2d();
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return Vec3::zero();
     }
+
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
 }
 
 pub fn reflect(v: Vec3, n: Vec3) -> Vec3 {